@@ -0,0 +1,100 @@
+//! Detection of whether anyone is using the device, so the controller can turn it off when idle
+
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use slog::{debug, warn, Logger};
+use tokio::process::Command;
+use tokio::time;
+
+use samwise_proto::{Activity, Session};
+
+use crate::config::AgentConfiguration;
+
+/// Runs a check command, returning what it printed. Returns `None` if the command is not configured.
+async fn check_output(command: &Option<Vec<String>>) -> Option<Result<String>> {
+    let command = match command {
+        Some(command) if !command.is_empty() => command,
+        _ => return None,
+    };
+
+    let result = async {
+        let output = Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .await
+            .with_context(|| format!("Could not run `{}`", command.join(" ")))?;
+        if !output.status.success() {
+            bail!("`{}` failed with {}", command.join(" "), output.status);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+    Some(result.await)
+}
+
+/// Parses a line of `who` output. The last field is the remote host in parentheses for remote sessions, or the X
+/// display for local graphical sessions.
+fn parse_session(line: &str) -> Option<Session> {
+    let user = line.split_whitespace().next()?.to_string();
+    let host = match line.trim_end().strip_suffix(')') {
+        Some(rest) => match rest.rfind('(') {
+            Some(start) if !rest[start + 1..].starts_with(':') => rest[start + 1..].to_string(),
+            _ => String::new(),
+        },
+        None => String::new(),
+    };
+    Some(Session { user, host })
+}
+
+/// Checks idle time and login sessions. Returns `None` if the idle time can't be determined.
+pub async fn check(logger: &Logger, config: &AgentConfiguration) -> Option<Activity> {
+    let idle_millis = match check_output(&config.idle_command).await? {
+        Ok(output) => match output.trim().parse::<u64>() {
+            Ok(millis) => millis,
+            Err(error) => {
+                warn!(logger, "Invalid idle time `{}`: {}", output.trim(), error);
+                return None;
+            }
+        },
+        Err(error) => {
+            warn!(logger, "Could not check idle time: {:#}", error);
+            return None;
+        }
+    };
+
+    let sessions = match check_output(&config.sessions_command).await {
+        Some(Ok(output)) => Some(output.lines().filter_map(parse_session).collect()),
+        Some(Err(error)) => {
+            warn!(logger, "Could not list sessions: {:#}", error);
+            None
+        }
+        None => None,
+    };
+
+    Some(Activity {
+        idle_seconds: idle_millis / 1000,
+        sessions_known: sessions.is_some(),
+        sessions: sessions.unwrap_or_default(),
+    })
+}
+
+/// Periodically checks idle time and login sessions, saving the results in `activity`.
+pub async fn monitor(
+    logger: Logger,
+    config: AgentConfiguration,
+    activity: Arc<Mutex<Option<Activity>>>,
+) {
+    let mut tick = time::interval(Duration::from_secs(config.activity_check_interval));
+    loop {
+        tick.tick().await;
+        let current = check(&logger, &config).await;
+        debug!(&logger, "Checked activity: {:?}", current);
+        *activity
+            .lock()
+            .expect("Thread panicked with activity mutex") = current;
+    }
+}
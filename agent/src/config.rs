@@ -1,5 +1,14 @@
 use serde::Deserialize;
 
+#[cfg(target_os = "linux")]
+use std::{env, path::Path};
+
+/// 15 minutes
+const DEFAULT_UPDATE_CHECK_INTERVAL: u64 = 15 * 60;
+
+/// 1 minute
+const DEFAULT_ACTIVITY_CHECK_INTERVAL: u64 = 60;
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct AgentConfiguration {
     pub listen_address: String,
@@ -13,6 +22,75 @@ pub struct AgentConfiguration {
 
     #[serde(default = "default_suspend_command")]
     pub suspend_command: Option<Vec<String>>,
+
+    /// Command that holds an inhibitor lock for as long as it runs. `{reason}` and `{seconds}` are replaced with the
+    /// reason for and duration of the hold.
+    #[serde(default = "default_inhibit_command")]
+    pub inhibit_command: Option<Vec<String>>,
+
+    #[serde(default = "default_display_on_command")]
+    pub display_on_command: Option<Vec<String>>,
+
+    #[serde(default = "default_display_off_command")]
+    pub display_off_command: Option<Vec<String>>,
+
+    /// systemd units whose journal entries are included when tailing logs. If empty, only the agent's own logs are
+    /// included.
+    #[serde(default)]
+    pub journal_units: Vec<String>,
+
+    /// Command that installs pending updates. If not set, updates can't be run through the agent.
+    pub update_command: Option<Vec<String>>,
+
+    /// Command that checks for pending updates. Following the `dnf check-update` convention, it should exit with
+    /// status 100 if updates are available and 0 if not.
+    #[serde(default = "default_update_check_command")]
+    pub update_check_command: Option<Vec<String>>,
+
+    /// Command that checks whether a reboot is needed to finish applying updates. Following the `needs-restarting -r`
+    /// convention, it should exit with status 1 if a reboot is required. On Linux, `/var/run/reboot-required` is also
+    /// checked.
+    #[serde(default = "default_reboot_check_command")]
+    pub reboot_check_command: Option<Vec<String>>,
+
+    /// How often to check for pending updates and reboots, in seconds
+    #[serde(default = "default_update_check_interval")]
+    pub update_check_interval: u64,
+
+    /// Command that prints how long it's been since the last local user input, in milliseconds. If not set, the
+    /// controller can't tell whether the device is idle.
+    #[serde(default = "default_idle_command")]
+    pub idle_command: Option<Vec<String>>,
+
+    /// Command that lists login sessions in the format of `who`, with the remote host in parentheses at the end of
+    /// each line for remote sessions.
+    #[serde(default = "default_sessions_command")]
+    pub sessions_command: Option<Vec<String>>,
+
+    /// How often to check idle time and login sessions, in seconds
+    #[serde(default = "default_activity_check_interval")]
+    pub activity_check_interval: u64,
+
+    /// Connection to the controller, used to forward requests made locally on this device. If not set, local requests
+    /// are not accepted.
+    pub controller: Option<ControllerConfiguration>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ControllerConfiguration {
+    /// URI of the controller's RPC service, for example `http://faramir.local:3031`
+    pub address: String,
+
+    /// ID this device is configured under on the controller
+    pub device_id: String,
+
+    /// Loopback address to accept local requests (such as `samwise-agent switch`) on
+    #[serde(default = "default_local_address")]
+    pub local_address: String,
+}
+
+fn default_local_address() -> String {
+    "127.0.0.1:3032".to_string()
 }
 
 // Note: using AppleScript on macOS because it's supposedly more like a GUI shutdown
@@ -68,3 +146,175 @@ fn default_suspend_command() -> Option<Vec<String>> {
         }
     }
 }
+
+/// System-specific default for holding an inhibitor lock.
+/// - On Linux, use `systemd-inhibit` to take a logind `sleep:idle` lock
+/// - On macOS, use `caffeinate`
+/// - On Windows, no default because there's no built-in command-line equivalent
+fn default_inhibit_command() -> Option<Vec<String>> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            Some(vec![
+                "systemd-inhibit".to_string(),
+                "--what=sleep:idle".to_string(),
+                "--who=samwise".to_string(),
+                "--why={reason}".to_string(),
+                "--mode=block".to_string(),
+                "sleep".to_string(),
+                "{seconds}".to_string(),
+            ])
+        } else if #[cfg(target_os = "macos")] {
+            Some(vec!["caffeinate".to_string(), "-i".to_string(), "-t".to_string(), "{seconds}".to_string()])
+        } else {
+            None
+        }
+    }
+}
+
+/// Display environments the agent knows how to control on Linux
+#[cfg(target_os = "linux")]
+enum DisplayEnvironment {
+    /// Raspberry Pi firmware, controlled with `vcgencmd`
+    RaspberryPi,
+    /// An X11 session, controlled with DPMS via `xset`
+    X11,
+}
+
+#[cfg(target_os = "linux")]
+fn display_environment() -> Option<DisplayEnvironment> {
+    if Path::new("/usr/bin/vcgencmd").exists() {
+        Some(DisplayEnvironment::RaspberryPi)
+    } else if env::var_os("DISPLAY").is_some() {
+        Some(DisplayEnvironment::X11)
+    } else {
+        None
+    }
+}
+
+/// System-specific default for turning the display on.
+/// - On Linux, use `vcgencmd` on a Raspberry Pi or `xset` under X11. Wayland compositors need an explicit command,
+///   since `wlr-randr` must be told which output to control (for example, `["wlr-randr", "--output", "HDMI-A-1",
+///   "--on"]`)
+/// - On macOS, use `caffeinate` to briefly declare user activity
+/// - On Windows, no default because there's no built-in command-line equivalent
+fn default_display_on_command() -> Option<Vec<String>> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            match display_environment() {
+                Some(DisplayEnvironment::RaspberryPi) => Some(vec!["vcgencmd".to_string(), "display_power".to_string(), "1".to_string()]),
+                Some(DisplayEnvironment::X11) => Some(vec!["xset".to_string(), "dpms".to_string(), "force".to_string(), "on".to_string()]),
+                None => None,
+            }
+        } else if #[cfg(target_os = "macos")] {
+            Some(vec!["caffeinate".to_string(), "-u".to_string(), "-t".to_string(), "1".to_string()])
+        } else {
+            None
+        }
+    }
+}
+
+/// System-specific default for turning the display off.
+/// - On Linux, use `vcgencmd` on a Raspberry Pi or `xset` under X11. As with `default_display_on_command`, Wayland
+///   compositors need an explicit command
+/// - On macOS, use `pmset`
+/// - On Windows, no default because there's no built-in command-line equivalent
+fn default_display_off_command() -> Option<Vec<String>> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            match display_environment() {
+                Some(DisplayEnvironment::RaspberryPi) => Some(vec!["vcgencmd".to_string(), "display_power".to_string(), "0".to_string()]),
+                Some(DisplayEnvironment::X11) => Some(vec!["xset".to_string(), "dpms".to_string(), "force".to_string(), "off".to_string()]),
+                None => None,
+            }
+        } else if #[cfg(target_os = "macos")] {
+            Some(vec!["pmset".to_string(), "displaysleepnow".to_string()])
+        } else {
+            None
+        }
+    }
+}
+
+fn default_update_check_interval() -> u64 {
+    DEFAULT_UPDATE_CHECK_INTERVAL
+}
+
+/// System-specific default for checking for pending updates.
+/// - On Linux, use `dnf` or `apt-get`, whichever is installed
+/// - On other systems, no default
+fn default_update_check_command() -> Option<Vec<String>> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            if Path::new("/usr/bin/dnf").exists() {
+                Some(vec!["dnf".to_string(), "check-update".to_string(), "--quiet".to_string()])
+            } else if Path::new("/usr/bin/apt-get").exists() {
+                // apt-get doesn't signal pending updates in its exit status, so adapt it to the dnf convention
+                Some(vec![
+                    "sh".to_string(),
+                    "-c".to_string(),
+                    "apt-get --simulate --quiet upgrade | grep --quiet '^Inst ' && exit 100 || exit 0".to_string(),
+                ])
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+}
+
+/// System-specific default for checking whether a reboot is required.
+/// - On Linux, use `needs-restarting` if installed. Debian-based systems instead rely on `/var/run/reboot-required`
+/// - On other systems, no default
+fn default_reboot_check_command() -> Option<Vec<String>> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            if Path::new("/usr/bin/needs-restarting").exists() {
+                Some(vec!["needs-restarting".to_string(), "-r".to_string()])
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+}
+
+fn default_activity_check_interval() -> u64 {
+    DEFAULT_ACTIVITY_CHECK_INTERVAL
+}
+
+/// System-specific default for checking idle time.
+/// - On Linux, use `xprintidle` under X11 if installed
+/// - On macOS, read `HIDIdleTime` from `ioreg`, which is in nanoseconds
+/// - On Windows, no default because there's no built-in command-line equivalent
+fn default_idle_command() -> Option<Vec<String>> {
+    cfg_if::cfg_if! {
+        if #[cfg(target_os = "linux")] {
+            match display_environment() {
+                Some(DisplayEnvironment::X11) if Path::new("/usr/bin/xprintidle").exists() => Some(vec!["xprintidle".to_string()]),
+                _ => None,
+            }
+        } else if #[cfg(target_os = "macos")] {
+            Some(vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "ioreg -c IOHIDSystem | awk '/HIDIdleTime/ { print int($NF / 1000000); exit }'".to_string(),
+            ])
+        } else {
+            None
+        }
+    }
+}
+
+/// System-specific default for listing login sessions.
+/// - On Linux and macOS, use `who`
+/// - On Windows, no default because `query user` doesn't report where sessions are from
+fn default_sessions_command() -> Option<Vec<String>> {
+    cfg_if::cfg_if! {
+        if #[cfg(any(target_os = "linux", target_os = "macos"))] {
+            Some(vec!["who".to_string()])
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Inhibitor locks held on behalf of the controller
+
+use std::collections::BTreeMap;
+use std::process::Child;
+use std::time::{Duration, Instant};
+
+use samwise_proto::Hold;
+
+/// An inhibitor lock, held for as long as its process is running.
+struct ActiveHold {
+    reason: String,
+    expires: Instant,
+    process: Child,
+}
+
+/// Tracks the inhibitor locks currently held by the agent.
+#[derive(Default)]
+pub struct Holds {
+    next_id: u64,
+    active: BTreeMap<u64, ActiveHold>,
+}
+
+impl Holds {
+    /// Records a new hold, returning its description.
+    pub fn insert(&mut self, reason: String, ttl: Duration, process: Child) -> Hold {
+        self.next_id += 1;
+        let id = self.next_id;
+        let hold = Hold {
+            id,
+            reason: reason.clone(),
+            expires_in_seconds: ttl.as_secs(),
+        };
+        self.active.insert(
+            id,
+            ActiveHold {
+                reason,
+                expires: Instant::now() + ttl,
+                process,
+            },
+        );
+        hold
+    }
+
+    /// Releases a hold, returning `false` if it did not exist.
+    pub fn release(&mut self, id: u64) -> bool {
+        match self.active.remove(&id) {
+            Some(mut hold) => {
+                // Ignore errors, since they mean the process has already exited
+                let _ = hold.process.kill();
+                let _ = hold.process.wait();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Describes the holds which are still active, forgetting any that have expired.
+    pub fn list(&mut self) -> Vec<Hold> {
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .active
+            .iter_mut()
+            .filter_map(|(id, hold)| {
+                // The process exiting early means the lock was released out from under us
+                let exited = !matches!(hold.process.try_wait(), Ok(None));
+                if exited || hold.expires <= now {
+                    Some(*id)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        for id in expired {
+            self.release(id);
+        }
+
+        self.active
+            .iter()
+            .map(|(id, hold)| Hold {
+                id: *id,
+                reason: hold.reason.clone(),
+                expires_in_seconds: hold.expires.saturating_duration_since(now).as_secs(),
+            })
+            .collect()
+    }
+}
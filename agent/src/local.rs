@@ -0,0 +1,79 @@
+//! Local endpoint for requests made on the device itself, which are forwarded to the controller.
+
+use anyhow::{Context, Result};
+use slog::{info, o, warn, Logger};
+use tonic::transport::{Channel, Endpoint};
+use tonic::{Request, Response, Status};
+
+use samwise_proto::controller_client::ControllerClient;
+use samwise_proto::controller_server::Controller;
+use samwise_proto::{ListTargetsRequest, ListTargetsResponse, SwitchRequest, SwitchResponse};
+
+use crate::config::ControllerConfiguration;
+
+pub struct LocalImpl {
+    logger: Logger,
+    device_id: String,
+    client: ControllerClient<Channel>,
+}
+
+impl LocalImpl {
+    pub fn new(config: &ControllerConfiguration, logger: &Logger) -> Result<LocalImpl> {
+        let endpoint = Endpoint::from_shared(config.address.clone())
+            .context("Malformed controller address")?;
+        let channel = endpoint.connect_lazy()?;
+
+        Ok(LocalImpl {
+            logger: logger.new(o!("controller" => config.address.clone())),
+            device_id: config.device_id.clone(),
+            client: ControllerClient::new(channel),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Controller for LocalImpl {
+    async fn switch(
+        &self,
+        request: Request<SwitchRequest>,
+    ) -> Result<Response<SwitchResponse>, Status> {
+        let target = request.into_inner().target;
+        info!(&self.logger, "Asking controller to switch to {}", target);
+
+        // Cloning Tonic clients is cheap, and avoids needing &mut self
+        let mut client = self.client.clone();
+        client
+            .switch(Request::new(SwitchRequest {
+                device: self.device_id.clone(),
+                target,
+            }))
+            .await
+            .map_err(|status| {
+                warn!(&self.logger, "Controller rejected switch: {}", status);
+                status
+            })
+    }
+
+    async fn list_targets(
+        &self,
+        _request: Request<ListTargetsRequest>,
+    ) -> Result<Response<ListTargetsResponse>, Status> {
+        let mut client = self.client.clone();
+        client
+            .list_targets(Request::new(ListTargetsRequest {
+                device: self.device_id.clone(),
+            }))
+            .await
+    }
+}
+
+/// Connects to the local endpoint of a running agent, for use by subcommands.
+pub async fn connect(config: &ControllerConfiguration) -> Result<ControllerClient<Channel>> {
+    let endpoint = Endpoint::from_shared(format!("http://{}", config.local_address))
+        .context("Malformed local address")?;
+    let channel = endpoint
+        .connect()
+        .await
+        .with_context(|| format!("Could not connect to agent at {}", config.local_address))?;
+    Ok(ControllerClient::new(channel))
+}
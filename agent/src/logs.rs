@@ -0,0 +1,154 @@
+//! Log tailing, for streaming the agent's logs and the systemd journal to the controller
+
+use std::fmt::{self, Write};
+use std::process::Stdio;
+
+use slog::{Drain, OwnedKVList, Record, KV};
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::process::{Child, ChildStdout, Command};
+use tokio::sync::{broadcast, mpsc};
+use tonic::Status;
+
+use samwise_proto::LogEntry;
+
+/// Number of log records buffered for each subscriber before older ones are dropped
+const LOG_BUFFER_SIZE: usize = 256;
+
+/// Size of the channel between the tailing task and the RPC response stream
+const STREAM_BUFFER_SIZE: usize = 16;
+
+/// Drain which formats records and publishes them to any subscribers, for tailing.
+pub struct BroadcastDrain {
+    tx: broadcast::Sender<String>,
+}
+
+impl BroadcastDrain {
+    pub fn new() -> BroadcastDrain {
+        let (tx, _) = broadcast::channel(LOG_BUFFER_SIZE);
+        BroadcastDrain { tx }
+    }
+
+    /// Handle for subscribing to records logged through this drain.
+    pub fn publisher(&self) -> broadcast::Sender<String> {
+        self.tx.clone()
+    }
+}
+
+impl Drain for BroadcastDrain {
+    type Ok = ();
+    type Err = slog::Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), slog::Never> {
+        // Skip formatting if nobody is listening
+        if self.tx.receiver_count() == 0 {
+            return Ok(());
+        }
+
+        let mut line = format!("{} {}", record.level().as_short_str(), record.msg());
+        let mut serializer = LineSerializer(&mut line);
+        // Formatting into a String can't fail
+        let _ = record.kv().serialize(record, &mut serializer);
+        let _ = values.serialize(record, &mut serializer);
+
+        // Failing to send just means all subscribers disconnected since the check above
+        let _ = self.tx.send(line);
+        Ok(())
+    }
+}
+
+/// Appends key-value pairs to a log line as `key=value`
+struct LineSerializer<'a>(&'a mut String);
+
+impl<'a> slog::Serializer for LineSerializer<'a> {
+    fn emit_arguments(&mut self, key: slog::Key, val: &fmt::Arguments) -> slog::Result {
+        write!(self.0, " {}={}", key, val)?;
+        Ok(())
+    }
+}
+
+/// A running `journalctl` process, which is killed when dropped.
+struct Journal {
+    _process: Child,
+    lines: Lines<BufReader<ChildStdout>>,
+}
+
+impl Journal {
+    /// Starts following the journal for `units`.
+    fn follow(units: &[String]) -> std::io::Result<Journal> {
+        let mut cmd = Command::new("journalctl");
+        cmd.args(["--follow", "--lines=0", "--output=short"]);
+        for unit in units {
+            cmd.arg("--unit").arg(unit);
+        }
+
+        let mut process = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()?;
+        let stdout = process
+            .stdout
+            .take()
+            .expect("journalctl stdout should have been piped");
+
+        Ok(Journal {
+            _process: process,
+            lines: BufReader::new(stdout).lines(),
+        })
+    }
+}
+
+/// Streams new log records and, if any units are given, journal entries, until the receiver is dropped.
+pub fn tail(
+    logs: &broadcast::Sender<String>,
+    journal_units: &[String],
+) -> Result<mpsc::Receiver<Result<LogEntry, Status>>, Status> {
+    let mut logs = logs.subscribe();
+    let mut journal = if journal_units.is_empty() {
+        None
+    } else {
+        Some(
+            Journal::follow(journal_units)
+                .map_err(|_| Status::internal("Could not start journalctl"))?,
+        )
+    };
+
+    let (mut tx, rx) = mpsc::channel(STREAM_BUFFER_SIZE);
+    tokio::spawn(async move {
+        loop {
+            let entry = tokio::select! {
+                record = logs.recv() => match record {
+                    Ok(line) => LogEntry { source: "agent".to_string(), line },
+                    Err(broadcast::RecvError::Lagged(count)) => LogEntry {
+                        source: "agent".to_string(),
+                        line: format!("({} log records dropped)", count),
+                    },
+                    Err(broadcast::RecvError::Closed) => break,
+                },
+                line = next_line(&mut journal), if journal.is_some() => match line {
+                    Some(line) => LogEntry { source: "journal".to_string(), line },
+                    None => {
+                        journal = None;
+                        continue;
+                    }
+                },
+            };
+
+            // The receiver closing means the client went away
+            if tx.send(Ok(entry)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(rx)
+}
+
+/// Reads the next line of journal output, if there is any
+async fn next_line(journal: &mut Option<Journal>) -> Option<String> {
+    match journal {
+        Some(journal) => journal.lines.next_line().await.ok().flatten(),
+        None => None,
+    }
+}
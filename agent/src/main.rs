@@ -1,7 +1,9 @@
 use std::path::PathBuf;
-use std::process::Command;
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use anyhow::{Context, Error};
+use anyhow::{anyhow, Context, Error};
 use itertools::Itertools;
 use slog::{debug, error, info, o, warn, Drain, Logger};
 use structopt::StructOpt;
@@ -10,14 +12,28 @@ use tonic::transport::Server;
 use tonic::{Request, Response, Status};
 
 use samwise_proto::agent_server::{Agent, AgentServer};
+use samwise_proto::controller_server::ControllerServer;
 use samwise_proto::{
-    PingRequest, PingResponse, RebootRequest, RebootResponse, ShutdownRequest, ShutdownResponse,
-    SuspendRequest, SuspendResponse,
+    Activity, DisplayOffRequest, DisplayOffResponse, DisplayOnRequest, DisplayOnResponse,
+    DisplayState, HoldRequest, HoldResponse, ListTargetsRequest, LogEntry, PingRequest,
+    PingResponse, RebootRequest, RebootResponse, ReleaseRequest, ReleaseResponse, ShutdownRequest,
+    ShutdownResponse, SuspendRequest, SuspendResponse, SwitchRequest, TailLogsRequest,
+    UpdateRequest, UpdateResponse,
 };
+use tokio::sync::{broadcast, mpsc};
 
+mod activity;
 mod config;
+mod hold;
+mod local;
+mod logs;
+mod updates;
 
 use config::AgentConfiguration;
+use hold::Holds;
+use local::LocalImpl;
+use logs::BroadcastDrain;
+use updates::UpdateStatus;
 
 #[derive(StructOpt)]
 #[structopt(name = "samwise-agent", about = "Local agent for Samwise")]
@@ -25,27 +41,58 @@ struct Args {
     #[structopt(long = "--config")]
     #[structopt(parse(from_os_str))]
     pub config_path: PathBuf,
+
+    #[structopt(subcommand)]
+    pub command: Option<Subcommand>,
+}
+
+/// Requests to send to an already-running agent. If no command is given, runs the agent itself.
+#[derive(StructOpt)]
+enum Subcommand {
+    /// Ask the controller to switch this device to another target
+    Switch { target: String },
+
+    /// List the targets the controller knows for this device
+    ListTargets,
 }
 
-fn create_logger() -> Logger {
+/// Creates the root logger, along with a handle for tailing its output
+fn create_logger() -> (Logger, broadcast::Sender<String>) {
     let drain = slog_term::TermDecorator::new().build();
     let drain = slog_term::FullFormat::new(drain)
         .use_local_timestamp()
         .build()
         .fuse();
+    let broadcast = BroadcastDrain::new();
+    let logs = broadcast.publisher();
+    let drain = slog::Duplicate::new(drain, broadcast).fuse();
     let drain = slog_async::Async::new(drain).build().fuse();
-    Logger::root(drain, o!())
+    (Logger::root(drain, o!()), logs)
 }
 
 struct AgentImpl {
     logger: Logger,
     config: AgentConfiguration,
+    holds: Mutex<Holds>,
+    /// State the display was last set to
+    display: Mutex<DisplayState>,
+    /// Publisher for the agent's own log records
+    logs: broadcast::Sender<String>,
+    /// Most recent check for pending updates and reboots
+    updates: Arc<Mutex<UpdateStatus>>,
+    /// Most recent check of idle time and login sessions
+    activity: Arc<Mutex<Option<Activity>>>,
 }
 
 impl AgentImpl {
     /// Start a command in the background. Fails if the command line is empty or starting the process fails, but does
     /// not wait for the process to complete.
     fn spawn(&self, command: &[String]) -> Result<(), Status> {
+        self.start(command).map(|_| ())
+    }
+
+    /// Start a command in the background, returning a handle to the running process.
+    fn start(&self, command: &[String]) -> Result<Child, Status> {
         if command.is_empty() {
             warn!(&self.logger, "Tried to run an empty command");
             return Err(Status::unimplemented("Command not provided"));
@@ -57,26 +104,70 @@ impl AgentImpl {
         let mut cmd = Command::new(&command[0]);
         cmd.args(&command[1..]);
 
-        if let Err(error) = cmd.spawn() {
+        cmd.spawn().map_err(|error| {
             error!(
                 &self.logger,
                 "Could not start `{}`: {:?}",
                 command.iter().format(" "),
                 error
             );
-            Err(Status::internal("Spawning command failed"))
-        } else {
-            Ok(())
+            Status::internal("Spawning command failed")
+        })
+    }
+
+    fn holds(&self) -> std::sync::MutexGuard<Holds> {
+        self.holds.lock().expect("Thread panicked with holds mutex")
+    }
+
+    fn updates(&self) -> std::sync::MutexGuard<UpdateStatus> {
+        self.updates
+            .lock()
+            .expect("Thread panicked with update status mutex")
+    }
+
+    /// Runs the command to set the display to `state`, remembering it on success.
+    fn set_display(&self, state: DisplayState) -> Result<(), Status> {
+        let command = match state {
+            DisplayState::DisplayOn => &self.config.display_on_command,
+            _ => &self.config.display_off_command,
+        };
+        match command {
+            Some(ref command) => self.spawn(command.as_slice())?,
+            None => {
+                warn!(&self.logger, "Display command not set");
+                return Err(Status::unimplemented("Display command not set"));
+            }
         }
+
+        *self
+            .display
+            .lock()
+            .expect("Thread panicked with display mutex") = state;
+        Ok(())
     }
 }
 
 #[tonic::async_trait]
 impl Agent for AgentImpl {
+    type TailLogsStream = mpsc::Receiver<Result<LogEntry, Status>>;
+
     async fn ping(&self, _request: Request<PingRequest>) -> Result<Response<PingResponse>, Status> {
         debug!(&self.logger, "Got a ping request");
+        let updates = *self.updates();
         let reply = PingResponse {
             current_target: self.config.target_name.clone(),
+            holds: self.holds().list(),
+            display: *self
+                .display
+                .lock()
+                .expect("Thread panicked with display mutex") as i32,
+            reboot_required: updates.reboot_required,
+            updates_pending: updates.updates_pending,
+            activity: self
+                .activity
+                .lock()
+                .expect("Thread panicked with activity mutex")
+                .clone(),
         };
         Ok(Response::new(reply))
     }
@@ -125,12 +216,160 @@ impl Agent for AgentImpl {
         }
         Ok(Response::new(SuspendResponse {}))
     }
+
+    async fn hold(&self, request: Request<HoldRequest>) -> Result<Response<HoldResponse>, Status> {
+        let request = request.into_inner();
+        if request.ttl_seconds == 0 {
+            return Err(Status::invalid_argument("Hold must have a TTL"));
+        }
+
+        info!(
+            &self.logger,
+            "Holding for {}s: {}", request.ttl_seconds, request.reason
+        );
+        let command: Vec<String> = match self.config.inhibit_command {
+            Some(ref command) => command
+                .iter()
+                .map(|arg| {
+                    arg.replace("{reason}", &request.reason)
+                        .replace("{seconds}", &request.ttl_seconds.to_string())
+                })
+                .collect(),
+            None => {
+                warn!(&self.logger, "Inhibit command not set");
+                return Err(Status::unimplemented("Inhibit command not set"));
+            }
+        };
+
+        let process = self.start(&command)?;
+        let hold = self.holds().insert(
+            request.reason,
+            Duration::from_secs(request.ttl_seconds),
+            process,
+        );
+        Ok(Response::new(HoldResponse { hold: Some(hold) }))
+    }
+
+    async fn display_on(
+        &self,
+        _request: Request<DisplayOnRequest>,
+    ) -> Result<Response<DisplayOnResponse>, Status> {
+        info!(&self.logger, "Turning display on...");
+        self.set_display(DisplayState::DisplayOn)?;
+        Ok(Response::new(DisplayOnResponse {}))
+    }
+
+    async fn display_off(
+        &self,
+        _request: Request<DisplayOffRequest>,
+    ) -> Result<Response<DisplayOffResponse>, Status> {
+        info!(&self.logger, "Turning display off...");
+        self.set_display(DisplayState::DisplayOff)?;
+        Ok(Response::new(DisplayOffResponse {}))
+    }
+
+    async fn tail_logs(
+        &self,
+        _request: Request<TailLogsRequest>,
+    ) -> Result<Response<Self::TailLogsStream>, Status> {
+        info!(&self.logger, "Tailing logs");
+        let stream = logs::tail(&self.logs, &self.config.journal_units)?;
+        Ok(Response::new(stream))
+    }
+
+    async fn update(
+        &self,
+        _request: Request<UpdateRequest>,
+    ) -> Result<Response<UpdateResponse>, Status> {
+        info!(&self.logger, "Updating...");
+        let command = match self.config.update_command {
+            Some(ref command) if !command.is_empty() => command,
+            _ => {
+                warn!(&self.logger, "Update command not set");
+                return Err(Status::unimplemented("Update command not set"));
+            }
+        };
+
+        let result = updates::update(&self.logger, command)
+            .await
+            .map_err(|error| {
+                error!(&self.logger, "Could not run update command: {:?}", error);
+                Status::internal("Running update command failed")
+            })?;
+        if !result.success {
+            warn!(&self.logger, "Update command failed");
+        }
+
+        let status = updates::check(&self.logger, &self.config).await;
+        *self.updates() = status;
+
+        Ok(Response::new(UpdateResponse {
+            success: result.success,
+            reboot_required: status.reboot_required,
+            output: result.output,
+        }))
+    }
+
+    async fn release(
+        &self,
+        request: Request<ReleaseRequest>,
+    ) -> Result<Response<ReleaseResponse>, Status> {
+        let id = request.into_inner().id;
+        info!(&self.logger, "Releasing hold {}", id);
+        if self.holds().release(id) {
+            Ok(Response::new(ReleaseResponse {}))
+        } else {
+            Err(Status::not_found(format!("No such hold: {}", id)))
+        }
+    }
+}
+
+/// Runs a subcommand against the local endpoint of a running agent.
+async fn run_command(command: Subcommand, config: &AgentConfiguration) -> Result<(), Error> {
+    let controller = config
+        .controller
+        .as_ref()
+        .ok_or_else(|| anyhow!("No controller configured"))?;
+    let mut client = local::connect(controller).await?;
+
+    match command {
+        Subcommand::Switch { target } => {
+            client
+                .switch(Request::new(SwitchRequest {
+                    device: controller.device_id.clone(),
+                    target: target.clone(),
+                }))
+                .await
+                .with_context(|| format!("Could not switch to {}", target))?;
+            println!("Switching to {}", target);
+        }
+        Subcommand::ListTargets => {
+            let response = client
+                .list_targets(Request::new(ListTargetsRequest {
+                    device: controller.device_id.clone(),
+                }))
+                .await
+                .context("Could not list targets")?;
+            for target in response.into_inner().targets {
+                if target.description.is_empty() {
+                    println!("{} ({})", target.id, target.display_name);
+                } else {
+                    println!(
+                        "{} ({}): {}",
+                        target.id, target.display_name, target.description
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let args = Args::from_args();
-    let logger = create_logger();
+    let (logger, logs) = create_logger();
 
     debug!(&logger, "Loading configuration"; "path" => args.config_path.display());
     let config_str = fs::read_to_string(&args.config_path)
@@ -139,11 +378,47 @@ async fn main() -> Result<(), Error> {
     let config: AgentConfiguration = toml::from_str(&config_str)
         .with_context(|| format!("Invalid config file {}", args.config_path.display()))?;
 
+    if let Some(command) = args.command {
+        return run_command(command, &config).await;
+    }
+
+    let update_status = Arc::new(Mutex::new(UpdateStatus::default()));
+    tokio::spawn(updates::monitor(
+        logger.clone(),
+        config.clone(),
+        update_status.clone(),
+    ));
+
+    let activity = Arc::new(Mutex::new(None));
+    tokio::spawn(activity::monitor(
+        logger.clone(),
+        config.clone(),
+        activity.clone(),
+    ));
+
     let addr = config.listen_address.parse()?;
-    Server::builder()
-        .add_service(AgentServer::new(AgentImpl { logger, config }))
-        .serve(addr)
-        .await?;
+    let agent = Server::builder()
+        .add_service(AgentServer::new(AgentImpl {
+            logger: logger.clone(),
+            config: config.clone(),
+            holds: Mutex::new(Holds::default()),
+            display: Mutex::new(DisplayState::DisplayUnknown),
+            logs,
+            updates: update_status,
+            activity,
+        }))
+        .serve(addr);
+
+    match config.controller {
+        Some(ref controller) => {
+            let local_addr = controller.local_address.parse()?;
+            let local = Server::builder()
+                .add_service(ControllerServer::new(LocalImpl::new(controller, &logger)?))
+                .serve(local_addr);
+            tokio::try_join!(agent, local)?;
+        }
+        None => agent.await?,
+    }
 
     Ok(())
 }
@@ -0,0 +1,115 @@
+//! Detection and installation of system updates
+
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use itertools::Itertools;
+use slog::{debug, info, warn, Logger};
+use tokio::process::Command;
+use tokio::time;
+
+use crate::config::AgentConfiguration;
+
+/// Number of lines of update output to report back to the controller
+const OUTPUT_LINES: usize = 20;
+
+/// Exit status of the update check command when updates are available
+const UPDATES_PENDING_STATUS: i32 = 100;
+
+/// Exit status of the reboot check command when a reboot is required
+const REBOOT_REQUIRED_STATUS: i32 = 1;
+
+/// Whether the device has updates to install or finish installing
+#[derive(Debug, Default, Clone, Copy)]
+pub struct UpdateStatus {
+    pub reboot_required: bool,
+    pub updates_pending: bool,
+}
+
+/// Result of running the update command
+pub struct UpdateResult {
+    pub success: bool,
+    pub output: String,
+}
+
+/// Runs a check command, returning its exit status. Returns `None` if the command is not configured or could not be
+/// run.
+async fn check_status(logger: &Logger, command: &Option<Vec<String>>) -> Option<i32> {
+    let command = match command {
+        Some(command) if !command.is_empty() => command,
+        _ => return None,
+    };
+
+    match Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .await
+    {
+        Ok(status) => status.code(),
+        Err(error) => {
+            warn!(
+                logger,
+                "Could not run `{}`: {:?}",
+                command.iter().format(" "),
+                error
+            );
+            None
+        }
+    }
+}
+
+/// Checks for pending updates and reboots.
+pub async fn check(logger: &Logger, config: &AgentConfiguration) -> UpdateStatus {
+    let updates_pending =
+        check_status(logger, &config.update_check_command).await == Some(UPDATES_PENDING_STATUS);
+
+    let mut reboot_required =
+        check_status(logger, &config.reboot_check_command).await == Some(REBOOT_REQUIRED_STATUS);
+    if cfg!(target_os = "linux") {
+        reboot_required |= tokio::fs::metadata("/var/run/reboot-required")
+            .await
+            .is_ok();
+    }
+
+    UpdateStatus {
+        reboot_required,
+        updates_pending,
+    }
+}
+
+/// Periodically checks for pending updates and reboots, saving the results in `status`.
+pub async fn monitor(logger: Logger, config: AgentConfiguration, status: Arc<Mutex<UpdateStatus>>) {
+    let mut tick = time::interval(Duration::from_secs(config.update_check_interval));
+    loop {
+        tick.tick().await;
+        let current = check(&logger, &config).await;
+        debug!(&logger, "Checked for updates: {:?}", current);
+        *status
+            .lock()
+            .expect("Thread panicked with update status mutex") = current;
+    }
+}
+
+/// Runs the update command to completion.
+pub async fn update(logger: &Logger, command: &[String]) -> std::io::Result<UpdateResult> {
+    info!(logger, "Running `{}`", command.iter().format(" "));
+    let output = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::null())
+        .output()
+        .await?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let lines: Vec<&str> = stdout.lines().chain(stderr.lines()).collect();
+    let output_tail = lines[lines.len().saturating_sub(OUTPUT_LINES)..].join("\n");
+
+    Ok(UpdateResult {
+        success: output.status.success(),
+        output: output_tail,
+    })
+}
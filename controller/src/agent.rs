@@ -1,17 +1,124 @@
-use anyhow::{Context, Result};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
 use slog::{o, trace, Logger};
 use tonic::transport::{Channel, Endpoint};
+use tonic::Streaming;
 
 use samwise_proto::agent_client::AgentClient;
-use samwise_proto::{PingRequest, RebootRequest, ShutdownRequest, SuspendRequest};
+use samwise_proto::{
+    DisplayOffRequest, DisplayOnRequest, HoldRequest, LogEntry, PingRequest, RebootRequest,
+    ReleaseRequest, ShutdownRequest, SuspendRequest, TailLogsRequest, UpdateRequest,
+};
 
 use crate::id::TargetId;
 
 pub enum AgentStatus {
-    Active(TargetId),
+    Active(TargetId, AgentReport),
     Inactive,
 }
 
+/// Additional details reported by a running agent.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct AgentReport {
+    /// Inhibitor locks currently held on the device
+    pub holds: Vec<Hold>,
+
+    /// State the agent last set the display to
+    pub display: DisplayState,
+
+    /// Whether the device needs to reboot to finish applying updates
+    pub reboot_required: bool,
+
+    /// Whether there are updates available to install
+    pub updates_pending: bool,
+
+    /// Whether anyone is using the device, if the agent can tell
+    pub activity: Option<Activity>,
+}
+
+/// Idle time and login sessions reported by the agent
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Activity {
+    /// Time since the last local user input
+    pub idle: Duration,
+    /// Login sessions, if the agent could list them
+    pub sessions: Option<Vec<Session>>,
+}
+
+impl From<samwise_proto::Activity> for Activity {
+    fn from(activity: samwise_proto::Activity) -> Self {
+        Activity {
+            idle: Duration::from_secs(activity.idle_seconds),
+            sessions: if activity.sessions_known {
+                Some(activity.sessions.into_iter().map(Session::from).collect())
+            } else {
+                None
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Session {
+    pub user: String,
+    /// Host a remote session is connected from, or `None` for a local session
+    pub host: Option<String>,
+}
+
+impl From<samwise_proto::Session> for Session {
+    fn from(session: samwise_proto::Session) -> Self {
+        Session {
+            user: session.user,
+            host: Some(session.host).filter(|host| !host.is_empty()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum DisplayState {
+    #[default]
+    Unknown,
+    On,
+    Off,
+}
+
+impl From<samwise_proto::DisplayState> for DisplayState {
+    fn from(state: samwise_proto::DisplayState) -> Self {
+        match state {
+            samwise_proto::DisplayState::DisplayUnknown => DisplayState::Unknown,
+            samwise_proto::DisplayState::DisplayOn => DisplayState::On,
+            samwise_proto::DisplayState::DisplayOff => DisplayState::Off,
+        }
+    }
+}
+
+/// Outcome of installing updates through the agent
+pub struct UpdateResult {
+    pub success: bool,
+    pub reboot_required: bool,
+    /// The last few lines of output from the update command
+    pub output: String,
+}
+
+/// An inhibitor lock held by the agent, which keeps the device from suspending while idle.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Hold {
+    pub id: u64,
+    pub reason: String,
+    pub expires: Instant,
+}
+
+impl From<samwise_proto::Hold> for Hold {
+    fn from(hold: samwise_proto::Hold) -> Self {
+        Hold {
+            id: hold.id,
+            reason: hold.reason,
+            expires: Instant::now() + Duration::from_secs(hold.expires_in_seconds),
+        }
+    }
+}
+
 // Cloning Tonic `Channel`s is cheap and encouraged, so cloning `AgentConnection` is as well
 
 #[derive(Clone)]
@@ -39,8 +146,17 @@ impl AgentConnection {
         let ping_response = self.client.ping(req).await;
         match ping_response {
             Ok(response) => {
-                let target_id = TargetId::new(response.into_inner().current_target);
-                AgentStatus::Active(target_id)
+                let response = response.into_inner();
+                let display = response.display().into();
+                let target_id = TargetId::new(response.current_target);
+                let report = AgentReport {
+                    holds: response.holds.into_iter().map(Hold::from).collect(),
+                    display,
+                    reboot_required: response.reboot_required,
+                    updates_pending: response.updates_pending,
+                    activity: response.activity.map(Activity::from),
+                };
+                AgentStatus::Active(target_id, report)
             }
             Err(error) => {
                 trace!(&self.logger, "Pinging agent failed: {}", error);
@@ -75,4 +191,75 @@ impl AgentConnection {
             .context("Shutting down via agent failed")?;
         Ok(())
     }
+
+    pub async fn display_on(&mut self) -> Result<()> {
+        let req = tonic::Request::new(DisplayOnRequest {});
+        self.client
+            .display_on(req)
+            .await
+            .context("Turning display on via agent failed")?;
+        Ok(())
+    }
+
+    pub async fn display_off(&mut self) -> Result<()> {
+        let req = tonic::Request::new(DisplayOffRequest {});
+        self.client
+            .display_off(req)
+            .await
+            .context("Turning display off via agent failed")?;
+        Ok(())
+    }
+
+    /// Takes an inhibitor lock on the device, which is released automatically after `ttl`.
+    pub async fn hold(&mut self, reason: String, ttl: Duration) -> Result<Hold> {
+        let req = tonic::Request::new(HoldRequest {
+            reason,
+            ttl_seconds: ttl.as_secs(),
+        });
+        let response = self
+            .client
+            .hold(req)
+            .await
+            .context("Holding via agent failed")?;
+        match response.into_inner().hold {
+            Some(hold) => Ok(hold.into()),
+            None => bail!("Agent did not describe hold"),
+        }
+    }
+
+    pub async fn release(&mut self, id: u64) -> Result<()> {
+        let req = tonic::Request::new(ReleaseRequest { id });
+        self.client
+            .release(req)
+            .await
+            .context("Releasing via agent failed")?;
+        Ok(())
+    }
+
+    /// Streams log entries from the agent, starting from when this is called.
+    pub async fn tail_logs(&mut self) -> Result<Streaming<LogEntry>> {
+        let req = tonic::Request::new(TailLogsRequest {});
+        let response = self
+            .client
+            .tail_logs(req)
+            .await
+            .context("Tailing logs via agent failed")?;
+        Ok(response.into_inner())
+    }
+
+    /// Installs updates, waiting for them to finish.
+    pub async fn update(&mut self) -> Result<UpdateResult> {
+        let req = tonic::Request::new(UpdateRequest {});
+        let response = self
+            .client
+            .update(req)
+            .await
+            .context("Updating via agent failed")?
+            .into_inner();
+        Ok(UpdateResult {
+            success: response.success,
+            reboot_required: response.reboot_required,
+            output: response.output,
+        })
+    }
 }
@@ -0,0 +1,354 @@
+//! Validation for problems in the configuration which would otherwise only show up once the controller is running
+
+use std::collections::HashMap;
+use std::path::Component;
+
+use pnet::datalink::{self, MacAddr};
+use tokio::fs::OpenOptions;
+use tonic::transport::Endpoint;
+
+use crate::config::Configuration;
+use crate::config::IdlePolicy;
+use crate::id::DeviceId;
+use crate::idle;
+use crate::schedule;
+
+/// Checks `config` for problems, returning a description of each one. Unlike loading the configuration, this reports
+/// every problem at once instead of stopping at the first.
+pub async fn check(config: &Configuration) -> Vec<String> {
+    let mut problems = Vec::new();
+    let interfaces = datalink::interfaces();
+    let mut macs: HashMap<MacAddr, Vec<DeviceId>> = HashMap::new();
+
+    let mut devices: Vec<_> = config.device_configs().collect();
+    devices.sort_by(|(a, _), (b, _)| a.as_string().cmp(b.as_string()));
+
+    for (id, device) in devices {
+        if let Err(error) = Endpoint::from_shared(device.agent().to_string()) {
+            problems.push(format!(
+                "{}: agent URI `{}` is malformed: {}",
+                id,
+                device.agent(),
+                error
+            ));
+        }
+
+        let interface_name = device
+            .interface()
+            .unwrap_or_else(|| config.default_interface());
+        match interfaces.iter().find(|i| i.name == interface_name) {
+            Some(interface) if interface.mac.is_none() => problems.push(format!(
+                "{}: network interface `{}` has no MAC address",
+                id, interface_name
+            )),
+            Some(_) => (),
+            None => problems.push(format!(
+                "{}: network interface `{}` does not exist",
+                id, interface_name
+            )),
+        }
+
+        macs.entry(device.mac_address())
+            .or_default()
+            .push(id.clone());
+
+        let grub_config = device.grub_config();
+        let escapes = grub_config.is_absolute()
+            || grub_config
+                .components()
+                .any(|component| component == Component::ParentDir);
+        if escapes {
+            problems.push(format!(
+                "{}: GRUB config `{}` is not under the TFTP directory",
+                id,
+                grub_config.display()
+            ));
+        } else {
+            let path = config.tftp_directory().join(grub_config);
+            // Opening for writing without truncating checks permissions without modifying the file
+            if let Err(error) = OpenOptions::new().write(true).open(&path).await {
+                problems.push(format!(
+                    "{}: GRUB config `{}` is missing or not writable: {}",
+                    id,
+                    path.display(),
+                    error
+                ));
+            }
+        }
+
+        if device.targets().is_empty() {
+            problems.push(format!("{}: no targets configured", id));
+        }
+
+        let mut names: HashMap<&str, &str> = HashMap::new();
+        let mut targets: Vec<_> = device.targets().iter().collect();
+        targets.sort_by_key(|(target, _)| target.as_str());
+        for (target, target_config) in targets {
+            for alias in target_config.aliases() {
+                if device.targets().contains_key(alias) {
+                    problems.push(format!(
+                        "{}: alias `{}` of target `{}` is also a target",
+                        id, alias, target
+                    ));
+                } else if let Some(other) = names.insert(alias, target) {
+                    problems.push(format!(
+                        "{}: alias `{}` is used by targets `{}` and `{}`",
+                        id, alias, other, target
+                    ));
+                }
+            }
+        }
+
+        if let Some(target) = device.update_target() {
+            if !device.targets().contains_key(target) {
+                problems.push(format!(
+                    "{}: update target `{}` is not a configured target",
+                    id, target
+                ));
+            }
+        }
+
+        if let Some(policy) = device.idle_policy() {
+            check_idle_policy(&mut problems, &id.to_string(), policy);
+        }
+        let mut targets: Vec<_> = device.targets().iter().collect();
+        targets.sort_by_key(|(target, _)| target.as_str());
+        for (target, target_config) in targets {
+            if let Some(policy) = target_config.idle_policy() {
+                check_idle_policy(&mut problems, &format!("{} target {}", id, target), policy);
+            }
+        }
+
+        if let Some(target) = device.default_target() {
+            if !device.targets().contains_key(target) {
+                problems.push(format!(
+                    "{}: default target `{}` is not a configured target",
+                    id, target
+                ));
+            }
+        }
+    }
+
+    check_dependencies(&mut problems, config);
+
+    let mut groups: Vec<_> = config.groups().collect();
+    groups.sort_by_key(|(name, _)| *name);
+    for (name, members) in groups {
+        for id in members {
+            if config.device_config(&id).is_none() {
+                problems.push(format!("group {}: device `{}` is not configured", name, id));
+            }
+        }
+    }
+
+    if let Some(timezone) = config.timezone() {
+        if let Err(error) = schedule::parse_timezone(timezone) {
+            problems.push(format!("{:#}", error));
+        }
+    }
+
+    let mut schedules: Vec<_> = config.schedules().collect();
+    schedules.sort_by_key(|(name, _)| *name);
+    for (name, schedule) in schedules {
+        if let Err(error) = schedule::parse_cron(schedule.cron()) {
+            problems.push(format!("schedule {}: {:#}", name, error));
+        }
+        if let Some(timezone) = schedule.timezone() {
+            if let Err(error) = schedule::parse_timezone(timezone) {
+                problems.push(format!("schedule {}: {:#}", name, error));
+            }
+        }
+        if let Err(error) = schedule::action(schedule.action(), schedule.target()) {
+            problems.push(format!("schedule {}: {:#}", name, error));
+        }
+
+        match config.device_config(&schedule.device()) {
+            None => problems.push(format!(
+                "schedule {}: device `{}` is not configured",
+                name,
+                schedule.device()
+            )),
+            Some(device) => {
+                if let Some(target) = schedule.target() {
+                    let known = device.targets().contains_key(target)
+                        || device.targets().values().any(|target_config| {
+                            target_config.aliases().iter().any(|a| a == target)
+                        });
+                    if !known {
+                        problems.push(format!(
+                            "schedule {}: target `{}` is not a configured target of `{}`",
+                            name,
+                            target,
+                            schedule.device()
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    let mut duplicates: Vec<_> = macs
+        .into_iter()
+        .filter(|(_, devices)| devices.len() > 1)
+        .collect();
+    duplicates.sort_by_key(|(mac, _)| mac.to_string());
+    for (mac, devices) in duplicates {
+        let names: Vec<String> = devices.iter().map(DeviceId::to_string).collect();
+        problems.push(format!(
+            "MAC address {} is used by multiple devices: {}",
+            mac,
+            names.join(", ")
+        ));
+    }
+
+    problems
+}
+
+/// Checks an idle policy, reporting problems under `context`.
+fn check_idle_policy(problems: &mut Vec<String>, context: &str, policy: &IdlePolicy) {
+    if !policy.keep_awake() && policy.idle().is_none() {
+        problems.push(format!(
+            "{}: idle policy must set `idle` unless it keeps the device awake",
+            context
+        ));
+    }
+    for time in policy.after().into_iter().chain(policy.before()) {
+        if let Err(error) = idle::parse_time(time) {
+            problems.push(format!("{}: idle policy: {:#}", context, error));
+        }
+    }
+}
+
+/// Checks that every device's dependencies are configured, can be booted, and don't depend on each other in a cycle.
+fn check_dependencies(problems: &mut Vec<String>, config: &Configuration) {
+    let mut devices: Vec<_> = config.device_configs().collect();
+    devices.sort_by(|(a, _), (b, _)| a.as_string().cmp(b.as_string()));
+
+    for (id, device) in devices.iter() {
+        for required in device.requires() {
+            if &required == id {
+                problems.push(format!("{}: device requires itself", id));
+                continue;
+            }
+            match config.device_config(&required) {
+                None => problems.push(format!(
+                    "{}: required device `{}` is not configured",
+                    id, required
+                )),
+                // Dependencies are started by booting their default target
+                Some(required_config) if required_config.default_target().is_none() => problems
+                    .push(format!(
+                        "{}: required device `{}` has no default target",
+                        id, required
+                    )),
+                Some(_) => (),
+            }
+        }
+
+        // Follow requirements from this device, looking for a path back to it
+        let mut visited = vec![];
+        let mut pending: Vec<DeviceId> = device
+            .requires()
+            .filter(|required| required != id)
+            .collect();
+        while let Some(next) = pending.pop() {
+            if &next == id {
+                problems.push(format!("{}: device indirectly requires itself", id));
+                break;
+            }
+            if visited.contains(&next) {
+                continue;
+            }
+            if let Some(next_config) = config.device_config(&next) {
+                pending.extend(next_config.requires());
+            }
+            visited.push(next);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses a configuration with a device for each of `devices`, given as its ID and the IDs it requires.
+    fn config(devices: &[(&str, &[&str])]) -> Configuration {
+        let mut source = String::from(
+            "listen_address = \"127.0.0.1:3000\"\n\
+             tftp_directory = \"/srv/tftp\"\n\
+             default_interface = \"eth0\"\n",
+        );
+        for (id, requires) in devices {
+            source.push_str(&format!(
+                "[devices.\"{}\"]\n\
+                 agent = \"http://{}:3001\"\n\
+                 mac_address = \"11:22:33:44:55:66\"\n\
+                 grub_config = \"{}.cfg\"\n\
+                 default_target = \"linux\"\n\
+                 requires = {:?}\n\
+                 [devices.\"{}\".targets.linux]\n\
+                 menu_entry = \"Linux\"\n",
+                id, id, id, requires, id
+            ));
+        }
+        toml::from_str(&source).unwrap()
+    }
+
+    fn dependency_problems(devices: &[(&str, &[&str])]) -> Vec<String> {
+        let mut problems = Vec::new();
+        check_dependencies(&mut problems, &config(devices));
+        problems
+    }
+
+    #[tokio::test]
+    async fn any_device_id_is_allowed() {
+        // Device files in the state directory are named safely whatever the ID
+        let problems = check(&config(&[("my desktop", &[]), ("../schedules", &[])])).await;
+        assert!(
+            problems
+                .iter()
+                .all(|problem| !problem.contains("device ID")),
+            "{:?}",
+            problems
+        );
+    }
+
+    #[test]
+    fn chain_is_allowed() {
+        let problems = dependency_problems(&[("a", &[]), ("b", &["a"]), ("c", &["a", "b"])]);
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn requiring_itself_is_a_problem() {
+        let problems = dependency_problems(&[("a", &["a"])]);
+        assert_eq!(problems, vec!["a: device requires itself"]);
+    }
+
+    #[test]
+    fn unknown_device_is_a_problem() {
+        let problems = dependency_problems(&[("a", &["nas"])]);
+        assert_eq!(problems, vec!["a: required device `nas` is not configured"]);
+    }
+
+    #[test]
+    fn cycle_is_reported_for_each_device_in_it() {
+        let problems =
+            dependency_problems(&[("a", &["c"]), ("b", &["a"]), ("c", &["b"]), ("d", &["a"])]);
+        assert_eq!(
+            problems,
+            vec![
+                "a: device indirectly requires itself",
+                "b: device indirectly requires itself",
+                "c: device indirectly requires itself",
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_dependency_is_not_a_cycle() {
+        let problems =
+            dependency_problems(&[("a", &[]), ("b", &["a"]), ("c", &["a"]), ("d", &["b", "c"])]);
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+}
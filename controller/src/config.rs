@@ -1,45 +1,120 @@
 use std::collections::HashMap;
+use std::env;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use pnet::datalink::MacAddr;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::fs;
+use toml::value::{Table, Value};
 
 use crate::id::DeviceId;
 
+/// Prefix for environment variables which override top-level configuration keys, for example
+/// `SAMWISE_LISTEN_ADDRESS` overrides `listen_address`.
+const ENV_PREFIX: &str = "SAMWISE_";
+
+/// Type of a configuration value set from the environment
+#[derive(Debug, Clone, Copy)]
+enum EnvType {
+    String,
+    Integer,
+    Boolean,
+}
+
+/// Top-level keys which can be overridden from the environment, and the type to read each one as.
+const ENV_KEYS: &[(&str, EnvType)] = &[
+    ("listen_address", EnvType::String),
+    ("rpc_listen_address", EnvType::String),
+    ("tftp_directory", EnvType::String),
+    ("default_interface", EnvType::String),
+    ("include", EnvType::String),
+    ("state_directory", EnvType::String),
+    ("history_max_events", EnvType::Integer),
+    ("history_max_age", EnvType::Integer),
+    ("poll_interval", EnvType::Integer),
+    ("boot_timeout", EnvType::Integer),
+    ("shutdown_timeout", EnvType::Integer),
+    ("suspend_timeout", EnvType::Integer),
+    ("maintenance_timeout", EnvType::Integer),
+    ("wake_interval", EnvType::Integer),
+    ("wake_attempts", EnvType::Integer),
+    ("adaptive_timeouts", EnvType::Boolean),
+    ("timezone", EnvType::String),
+];
+
 #[derive(Deserialize, Debug, Eq, PartialEq, Clone)]
 pub struct Configuration {
     listen_address: SocketAddr,
 
+    rpc_listen_address: Option<SocketAddr>,
+
     devices: HashMap<String, DeviceConfiguration>,
 
     tftp_directory: PathBuf,
 
     default_interface: String,
+
+    state_directory: Option<PathBuf>,
+
+    history_max_events: Option<usize>,
+
+    history_max_age: Option<u64>,
+
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+
+    timezone: Option<String>,
+
+    #[serde(default)]
+    schedules: HashMap<String, ScheduleConfiguration>,
+
+    #[serde(flatten)]
+    timing: TimingConfiguration,
 }
 
 impl Configuration {
+    /// Loads the configuration file at `source_path`, layering in device fragments from its `include` directory and
+    /// overrides from `SAMWISE_*` environment variables.
     pub async fn load_file<P: AsRef<Path>>(source_path: P) -> Result<Configuration> {
         let path = source_path.as_ref();
         let source = fs::read_to_string(path)
             .await
             .with_context(|| format!("Could not read configuration file {}", path.display()))?;
-        Configuration::load(&source)
-            .with_context(|| format!("Invalid configuration file: {}", path.display()))
-    }
+        let mut table: Table = toml::from_str(&source)
+            .with_context(|| format!("Invalid configuration file: {}", path.display()))?;
+
+        apply_env_overrides(&mut table, env::vars())?;
+
+        if let Some(include) = table.get("include") {
+            let include = match include.as_str() {
+                Some(include) => path
+                    .parent()
+                    .unwrap_or_else(|| Path::new("."))
+                    .join(include),
+                None => bail!("`include` must be a directory path"),
+            };
+            include_devices(&mut table, path, &include).await?;
+        }
 
-    pub fn load(source: &str) -> Result<Configuration> {
-        let config = toml::from_str(&source)?;
-        Ok(config)
+        Value::Table(table)
+            .try_into()
+            .with_context(|| format!("Invalid configuration file: {}", path.display()))
     }
 
     pub fn listen_address(&self) -> SocketAddr {
         self.listen_address
     }
 
-    pub fn devices<'a>(&'a self) -> impl Iterator<Item = DeviceId> + 'a {
+    /// Address to serve the RPC interface used by agents on. If not set, agents cannot forward requests to the
+    /// controller.
+    pub fn rpc_listen_address(&self) -> Option<SocketAddr> {
+        self.rpc_listen_address
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = DeviceId> + '_ {
         self.devices.keys().map(DeviceId::new)
     }
 
@@ -58,10 +133,210 @@ impl Configuration {
         &self.default_interface
     }
 
+    /// Directory to persist device state in across restarts, for example `/var/lib/samwise`. If not specified,
+    /// device state is only kept in memory.
+    pub fn state_directory(&self) -> Option<&Path> {
+        self.state_directory.as_deref()
+    }
+
+    /// Maximum number of history events to keep for each device.
+    pub fn history_max_events(&self) -> Option<usize> {
+        self.history_max_events
+    }
+
+    /// How long to keep history events for, if they should expire by age as well as count.
+    pub fn history_max_age(&self) -> Option<Duration> {
+        self.history_max_age.map(Duration::from_secs)
+    }
+
     /// Directory to place GRUB config files to serve over TFTP in, for example `/srv/tftp`.
     pub fn tftp_directory(&self) -> &Path {
         self.tftp_directory.as_path()
     }
+
+    /// Named groups of devices which can be acted on together.
+    pub fn groups(&self) -> impl Iterator<Item = (&str, Vec<DeviceId>)> {
+        self.groups
+            .iter()
+            .map(|(name, devices)| (name.as_str(), devices.iter().map(DeviceId::new).collect()))
+    }
+
+    /// Default timing for all devices.
+    pub fn timing(&self) -> &TimingConfiguration {
+        &self.timing
+    }
+
+    /// Name of the time zone schedules are in by default, for example `America/New_York`. If not specified, schedules
+    /// are in UTC.
+    pub fn timezone(&self) -> Option<&str> {
+        self.timezone.as_deref()
+    }
+
+    /// Named schedules of actions to perform on devices.
+    pub fn schedules(&self) -> impl Iterator<Item = (&str, &ScheduleConfiguration)> {
+        self.schedules
+            .iter()
+            .map(|(name, schedule)| (name.as_str(), schedule))
+    }
+}
+
+/// Overrides top-level keys in `table` with matching `SAMWISE_*` variables from `vars`. Each value is read as the type
+/// of the key it overrides, so that a value which happens to look like a number is still used as a string.
+fn apply_env_overrides<I: IntoIterator<Item = (String, String)>>(
+    table: &mut Table,
+    vars: I,
+) -> Result<()> {
+    for (name, value) in vars {
+        let key = match name.strip_prefix(ENV_PREFIX) {
+            Some(key) => key.to_lowercase(),
+            None => continue,
+        };
+        let env_type = match ENV_KEYS.iter().find(|(known, _)| *known == key) {
+            Some((_, env_type)) => *env_type,
+            None => continue,
+        };
+
+        let value = match env_type {
+            EnvType::String => Value::String(value),
+            EnvType::Integer => match value.parse() {
+                Ok(number) => Value::Integer(number),
+                Err(_) => bail!("{} must be an integer, but is `{}`", name, value),
+            },
+            EnvType::Boolean => match value.parse() {
+                Ok(flag) => Value::Boolean(flag),
+                Err(_) => bail!("{} must be true or false, but is `{}`", name, value),
+            },
+        };
+        table.insert(key, value);
+    }
+    Ok(())
+}
+
+/// Merges the devices defined by every `.toml` fragment in `include` into the `devices` table of the main configuration
+/// file at `path`. Each device may only be defined once across all files.
+async fn include_devices(table: &mut Table, path: &Path, include: &Path) -> Result<()> {
+    let mut fragments = Vec::new();
+    let mut entries = fs::read_dir(include)
+        .await
+        .with_context(|| format!("Could not read include directory {}", include.display()))?;
+    while let Some(entry) = entries.next_entry().await? {
+        let fragment = entry.path();
+        if fragment.extension().map_or(false, |ext| ext == "toml") {
+            fragments.push(fragment);
+        }
+    }
+    // Sort so that errors are reported consistently
+    fragments.sort();
+
+    let devices = table
+        .entry("devices")
+        .or_insert_with(|| Value::Table(Table::new()));
+    let devices = match devices.as_table_mut() {
+        Some(devices) => devices,
+        None => bail!("`devices` must be a table"),
+    };
+    let mut sources: HashMap<String, PathBuf> = devices
+        .keys()
+        .map(|id| (id.clone(), path.to_path_buf()))
+        .collect();
+
+    for fragment in fragments {
+        let source = fs::read_to_string(&fragment)
+            .await
+            .with_context(|| format!("Could not read configuration file {}", fragment.display()))?;
+        let mut value: Table = toml::from_str(&source)
+            .with_context(|| format!("Invalid configuration file: {}", fragment.display()))?;
+        let fragment_devices = match value.remove("devices") {
+            Some(Value::Table(fragment_devices)) => fragment_devices,
+            Some(_) => bail!("`devices` in {} must be a table", fragment.display()),
+            None => Table::new(),
+        };
+        if let Some(key) = value.keys().next() {
+            bail!(
+                "{} may only define devices, but has `{}`",
+                fragment.display(),
+                key
+            );
+        }
+
+        for (id, device) in fragment_devices {
+            if let Some(previous) = sources.get(&id) {
+                bail!(
+                    "Device `{}` is defined in both {} and {}",
+                    id,
+                    previous.display(),
+                    fragment.display()
+                );
+            }
+            sources.insert(id.clone(), fragment.clone());
+            devices.insert(id, device);
+        }
+    }
+
+    Ok(())
+}
+
+/// An action to perform on a device on a recurring schedule
+#[derive(Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct ScheduleConfiguration {
+    device: String,
+
+    /// Cron expression, with fields for seconds, minutes, hours, day of month, month, day of week, and optionally year
+    cron: String,
+
+    action: ActionName,
+
+    target: Option<String>,
+
+    timezone: Option<String>,
+
+    catch_up: Option<u64>,
+}
+
+impl ScheduleConfiguration {
+    /// Device to act on
+    pub fn device(&self) -> DeviceId {
+        DeviceId::new(&self.device)
+    }
+
+    /// When to perform the action, as a cron expression. For example, `0 0 16 * * Mon-Fri` is 4pm on weekdays.
+    pub fn cron(&self) -> &str {
+        &self.cron
+    }
+
+    pub fn action(&self) -> ActionName {
+        self.action
+    }
+
+    /// Target to run, for the `run` action.
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    /// Name of the time zone the cron expression is in, overriding the global time zone.
+    pub fn timezone(&self) -> Option<&str> {
+        self.timezone.as_deref()
+    }
+
+    /// How late a run missed while the controller was down can be and still be performed when it starts. By default,
+    /// missed runs are skipped.
+    pub fn catch_up(&self) -> Duration {
+        Duration::from_secs(self.catch_up.unwrap_or(0))
+    }
+}
+
+/// Action which can be scheduled, named as in the HTTP API
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionName {
+    Run,
+    Boot,
+    Reboot,
+    Suspend,
+    Shutdown,
+    Maintain,
+    DisplayOn,
+    DisplayOff,
 }
 
 /// Configuration for an individual device
@@ -77,6 +352,29 @@ pub struct DeviceConfiguration {
     grub_config: PathBuf,
 
     targets: HashMap<String, TargetConfiguration>,
+
+    update_target: Option<String>,
+
+    default_target: Option<String>,
+
+    #[serde(default)]
+    action_policy: ActionPolicy,
+
+    #[serde(default)]
+    wrong_target: WrongTargetPolicy,
+
+    wrong_target_retries: Option<u32>,
+
+    idle_policy: Option<IdlePolicy>,
+
+    #[serde(default)]
+    requires: Vec<String>,
+
+    #[serde(default)]
+    dependents: DependentsPolicy,
+
+    #[serde(flatten)]
+    timing: TimingConfiguration,
 }
 
 impl DeviceConfiguration {
@@ -105,11 +403,112 @@ impl DeviceConfiguration {
     pub fn targets(&self) -> &HashMap<String, TargetConfiguration> {
         &self.targets
     }
+
+    /// Target to boot into for maintenance, which installs updates through the agent.
+    pub fn update_target(&self) -> Option<&str> {
+        self.update_target.as_deref()
+    }
+
+    /// Target to boot when no particular target is requested, for example when waking the device from off.
+    pub fn default_target(&self) -> Option<&str> {
+        self.default_target.as_deref()
+    }
+
+    /// What to do with an action requested while the device is busy with another one.
+    pub fn action_policy(&self) -> ActionPolicy {
+        self.action_policy
+    }
+
+    /// What to do when the device boots a different target than the one requested.
+    pub fn wrong_target(&self) -> WrongTargetPolicy {
+        self.wrong_target
+    }
+
+    /// How many times to configure GRUB and reboot again under the `retry` wrong-target policy.
+    pub fn wrong_target_retries(&self) -> Option<u32> {
+        self.wrong_target_retries
+    }
+
+    /// When to turn the device off because nobody is using it. Can be overridden per target.
+    pub fn idle_policy(&self) -> Option<&IdlePolicy> {
+        self.idle_policy.as_ref()
+    }
+
+    /// Devices which must be running before this device runs a target, for example a NAS its files are on.
+    pub fn requires(&self) -> impl Iterator<Item = DeviceId> + '_ {
+        self.requires.iter().map(DeviceId::new)
+    }
+
+    /// What to do when this device is told to suspend or shut down while devices which require it are running.
+    pub fn dependents(&self) -> DependentsPolicy {
+        self.dependents
+    }
+
+    /// Timing for this device, overriding the global defaults.
+    pub fn timing(&self) -> &TimingConfiguration {
+        &self.timing
+    }
+}
+
+/// What to do with an action requested while a device is busy with another one. An action identical to one already
+/// in progress or pending is always merged with it.
+#[derive(Deserialize, Debug, Default, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionPolicy {
+    /// Run it after every other pending action
+    #[default]
+    Queue,
+    /// Run it instead of any pending actions
+    Replace,
+    /// Fail the request
+    Reject,
+}
+
+/// What to do when a device is told to suspend or shut down while devices which require it are still running
+#[derive(Deserialize, Debug, Default, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DependentsPolicy {
+    /// Fail the action
+    #[default]
+    Refuse,
+    /// Suspend or shut down the dependent devices first
+    Cascade,
+}
+
+/// What to do when a device boots a different target than the one requested, for example because GRUB fell back to
+/// another menu entry.
+#[derive(Deserialize, Debug, Default, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum WrongTargetPolicy {
+    /// Fail the action right away
+    #[default]
+    Fail,
+    /// Configure GRUB and reboot again, up to the configured number of retries
+    Retry,
+    /// Reboot into the last target the device was known to run successfully, then fail the action
+    Fallback,
 }
 
 #[derive(Deserialize, Debug, Eq, PartialEq, Clone)]
 pub struct TargetConfiguration {
     menu_entry: String,
+
+    display_name: Option<String>,
+
+    description: Option<String>,
+
+    icon: Option<String>,
+
+    #[serde(default)]
+    aliases: Vec<String>,
+
+    #[serde(default)]
+    hidden: bool,
+
+    idle_policy: Option<IdlePolicy>,
+
+    #[serde(flatten)]
+    timing: TimingConfiguration,
 }
 
 impl TargetConfiguration {
@@ -117,4 +516,328 @@ impl TargetConfiguration {
     pub fn menu_entry(&self) -> &str {
         &self.menu_entry
     }
+
+    /// Human-readable name for this target, for example `Windows 10`.
+    pub fn display_name(&self) -> Option<&str> {
+        self.display_name.as_deref()
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    /// Name or URL of an icon for dashboards to show with this target.
+    pub fn icon(&self) -> Option<&str> {
+        self.icon.as_deref()
+    }
+
+    /// Other names this target can be run by, for example `win` for `windows`.
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+
+    /// Whether to leave this target out of target listings. Hidden targets can still be run.
+    pub fn hidden(&self) -> bool {
+        self.hidden
+    }
+
+    /// When to turn the device off while it's running this target, overriding the device's idle policy.
+    pub fn idle_policy(&self) -> Option<&IdlePolicy> {
+        self.idle_policy.as_ref()
+    }
+
+    /// Timing for this target, overriding the device's settings.
+    pub fn timing(&self) -> &TimingConfiguration {
+        &self.timing
+    }
+}
+
+/// When to turn a device off automatically because nobody is using it. The device must have been idle for long enough,
+/// have no sessions that the policy cares about, have no holds, and (if a time window is given) it must be within the
+/// window, in the global time zone. A policy which keeps the device awake instead never turns it off, for example to
+/// exempt one target from the device's policy.
+#[derive(Deserialize, Debug, Eq, PartialEq, Clone)]
+pub struct IdlePolicy {
+    #[serde(default)]
+    keep_awake: bool,
+
+    #[serde(default)]
+    action: IdleAction,
+
+    /// Seconds since the last user input. Required unless the policy keeps the device awake.
+    idle: Option<u64>,
+
+    #[serde(default)]
+    sessions: SessionPolicy,
+
+    after: Option<String>,
+
+    before: Option<String>,
+}
+
+impl IdlePolicy {
+    /// Whether the device should never be turned off for being idle.
+    pub fn keep_awake(&self) -> bool {
+        self.keep_awake
+    }
+
+    pub fn action(&self) -> IdleAction {
+        self.action
+    }
+
+    /// How long the device must go without user input.
+    pub fn idle(&self) -> Option<Duration> {
+        self.idle.map(Duration::from_secs)
+    }
+
+    /// Which login sessions keep the device from being considered idle.
+    pub fn sessions(&self) -> SessionPolicy {
+        self.sessions
+    }
+
+    /// Time of day, as `HH:MM`, from which the policy applies. If `before` is earlier, the window wraps past midnight.
+    pub fn after(&self) -> Option<&str> {
+        self.after.as_deref()
+    }
+
+    /// Time of day, as `HH:MM`, until which the policy applies.
+    pub fn before(&self) -> Option<&str> {
+        self.before.as_deref()
+    }
+}
+
+/// What to do with an idle device
+#[derive(Deserialize, Debug, Default, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum IdleAction {
+    #[default]
+    Suspend,
+    Shutdown,
+}
+
+/// Which login sessions an idle device may still have
+#[derive(Deserialize, Debug, Default, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionPolicy {
+    /// Ignore sessions, and only go by idle time
+    Any,
+    /// Only local sessions, so that an open SSH session keeps the device on
+    #[default]
+    NoRemote,
+    /// No sessions at all
+    None,
+}
+
+/// How long to wait for devices, and how persistently to wake them. Can be set globally, and overridden per device and
+/// per target. All times are in seconds.
+#[derive(Deserialize, Debug, Default, Eq, PartialEq, Clone)]
+pub struct TimingConfiguration {
+    poll_interval: Option<u64>,
+    boot_timeout: Option<u64>,
+    shutdown_timeout: Option<u64>,
+    suspend_timeout: Option<u64>,
+    maintenance_timeout: Option<u64>,
+    wake_interval: Option<u64>,
+    wake_attempts: Option<u32>,
+    adaptive_timeouts: Option<bool>,
+}
+
+impl TimingConfiguration {
+    /// Fills in any values not set here from `defaults`.
+    pub fn or(&self, defaults: &TimingConfiguration) -> TimingConfiguration {
+        TimingConfiguration {
+            poll_interval: self.poll_interval.or(defaults.poll_interval),
+            boot_timeout: self.boot_timeout.or(defaults.boot_timeout),
+            shutdown_timeout: self.shutdown_timeout.or(defaults.shutdown_timeout),
+            suspend_timeout: self.suspend_timeout.or(defaults.suspend_timeout),
+            maintenance_timeout: self.maintenance_timeout.or(defaults.maintenance_timeout),
+            wake_interval: self.wake_interval.or(defaults.wake_interval),
+            wake_attempts: self.wake_attempts.or(defaults.wake_attempts),
+            adaptive_timeouts: self.adaptive_timeouts.or(defaults.adaptive_timeouts),
+        }
+    }
+
+    /// How often to ping the agent for state changes.
+    pub fn poll_interval(&self) -> Option<Duration> {
+        self.poll_interval.map(Duration::from_secs)
+    }
+
+    /// How long to wait for the device to boot or reboot into a target.
+    pub fn boot_timeout(&self) -> Option<Duration> {
+        self.boot_timeout.map(Duration::from_secs)
+    }
+
+    /// How long to wait for the device to shut down.
+    pub fn shutdown_timeout(&self) -> Option<Duration> {
+        self.shutdown_timeout.map(Duration::from_secs)
+    }
+
+    /// How long to wait for the device to suspend.
+    pub fn suspend_timeout(&self) -> Option<Duration> {
+        self.suspend_timeout.map(Duration::from_secs)
+    }
+
+    /// How long to wait for the agent to finish installing updates during maintenance.
+    pub fn maintenance_timeout(&self) -> Option<Duration> {
+        self.maintenance_timeout.map(Duration::from_secs)
+    }
+
+    /// How long to wait for the device to wake before resending the magic packet. The wait doubles after each packet.
+    pub fn wake_interval(&self) -> Option<Duration> {
+        self.wake_interval.map(Duration::from_secs)
+    }
+
+    /// How many magic packets to send before giving up on waking the device.
+    pub fn wake_attempts(&self) -> Option<u32> {
+        self.wake_attempts
+    }
+
+    /// Whether to derive timeouts which aren't set explicitly from how long the device has taken before.
+    pub fn adaptive_timeouts(&self) -> Option<bool> {
+        self.adaptive_timeouts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAIN: &str = r#"
+listen_address = "127.0.0.1:3000"
+tftp_directory = "/srv/tftp"
+default_interface = "eth0"
+include = "devices"
+"#;
+
+    fn device(id: &str) -> String {
+        format!(
+            "[devices.{}]\n\
+             agent = \"http://{}:3001\"\n\
+             mac_address = \"11:22:33:44:55:66\"\n\
+             grub_config = \"{}.cfg\"\n\
+             [devices.{}.targets.linux]\n\
+             menu_entry = \"Linux\"\n",
+            id, id, id, id
+        )
+    }
+
+    /// Writes a main configuration file defining `main_devices`, with an include directory holding `fragments`.
+    /// Returns the path to the main file.
+    fn write_config(name: &str, main_devices: &[&str], fragments: &[(&str, String)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("samwise-config-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("devices")).unwrap();
+
+        let mut main = MAIN.to_string();
+        for id in main_devices {
+            main.push_str(&device(id));
+        }
+        let path = dir.join("samwise.toml");
+        std::fs::write(&path, main).unwrap();
+        for (file, contents) in fragments {
+            std::fs::write(dir.join("devices").join(file), contents).unwrap();
+        }
+        path
+    }
+
+    fn device_ids(config: &Configuration) -> Vec<String> {
+        let mut ids: Vec<String> = config.devices().map(|id| id.to_string()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[tokio::test]
+    async fn includes_devices() {
+        let path = write_config(
+            "include",
+            &["desktop"],
+            &[
+                ("laptop.toml", device("laptop")),
+                ("servers.toml", device("nas") + &device("router")),
+                ("notes.txt", "not configuration".to_string()),
+            ],
+        );
+        let config = Configuration::load_file(&path).await.unwrap();
+        assert_eq!(
+            device_ids(&config),
+            vec!["desktop", "laptop", "nas", "router"]
+        );
+    }
+
+    #[tokio::test]
+    async fn device_defined_twice() {
+        let path = write_config(
+            "duplicate",
+            &["desktop"],
+            &[("desktop.toml", device("desktop"))],
+        );
+        let error = Configuration::load_file(&path).await.unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Device `desktop` is defined in both"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn fragment_with_other_settings() {
+        let path = write_config(
+            "settings",
+            &[],
+            &[("timing.toml", "boot_timeout = 60\n".to_string())],
+        );
+        let error = Configuration::load_file(&path).await.unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("may only define devices, but has `boot_timeout`"));
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_overrides_use_key_types() {
+        let mut table: Table = toml::from_str(MAIN).unwrap();
+        apply_env_overrides(
+            &mut table,
+            vars(&[
+                ("SAMWISE_DEFAULT_INTERFACE", "1"),
+                ("SAMWISE_TIMEZONE", "true"),
+                ("SAMWISE_BOOT_TIMEOUT", "90"),
+                ("SAMWISE_ADAPTIVE_TIMEOUTS", "false"),
+                ("SAMWISE_DEVICES", "ignored"),
+                ("POLL_INTERVAL", "5"),
+            ]),
+        )
+        .unwrap();
+
+        assert_eq!(table["default_interface"], Value::String("1".to_string()));
+        assert_eq!(table["timezone"], Value::String("true".to_string()));
+        assert_eq!(table["boot_timeout"], Value::Integer(90));
+        assert_eq!(table["adaptive_timeouts"], Value::Boolean(false));
+        assert!(!table.contains_key("devices") && !table.contains_key("poll_interval"));
+    }
+
+    #[test]
+    fn env_override_with_wrong_type() {
+        let mut table = Table::new();
+        let error = apply_env_overrides(&mut table, vars(&[("SAMWISE_WAKE_ATTEMPTS", "lots")]))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "SAMWISE_WAKE_ATTEMPTS must be an integer, but is `lots`"
+        );
+
+        let error = apply_env_overrides(&mut table, vars(&[("SAMWISE_ADAPTIVE_TIMEOUTS", "1")]))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "SAMWISE_ADAPTIVE_TIMEOUTS must be true or false, but is `1`"
+        );
+    }
 }
@@ -1,51 +1,349 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, Weak};
 
 use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use pnet::util::MacAddr;
-use slog::{debug, error, o, trace, Logger};
-use tokio::fs::OpenOptions;
+use serde::{Deserialize, Serialize};
+use slog::{debug, error, info, o, trace, warn, Logger};
+use tokio::fs::{self, OpenOptions};
 use tokio::io::*;
 use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::oneshot;
 use tokio::sync::watch;
+use tokio::sync::Mutex as AsyncMutex;
 use tokio::time;
-use tokio::time::Duration;
+use tokio::time::{Duration, Elapsed, Instant};
+use tonic::Streaming;
 
-use crate::agent::{AgentConnection, AgentStatus};
-use crate::config::{Configuration, TargetConfiguration};
-use crate::id::{DeviceId, TargetId};
+use samwise_proto::LogEntry;
+
+use crate::agent::{AgentConnection, AgentReport, AgentStatus, Hold};
+use crate::config::{
+    ActionPolicy, Configuration, DependentsPolicy, DeviceConfiguration, IdlePolicy,
+    TargetConfiguration, TimingConfiguration, WrongTargetPolicy,
+};
+use crate::history::{Event, EventKind, History};
+use crate::id::{ActionId, DeviceId, TargetId};
+use crate::maintenance::{LatestMaintenance, MaintenanceLog, MaintenanceReport};
+use crate::queue::{self, ActionQueue, ActionResult, Cancelled};
+use crate::schedule;
+use crate::stats::{self, Phase, Stats};
+use crate::store::{DeviceRecord, DeviceStore, StateStore};
+use crate::tracker::ActionTracker;
 use crate::wake::Waker;
 
 // Device structure:
 // - For each device, there are two tasks and 1+ (cheaply clonable) handles
-// - One task periodically pings the agent for updates, sending them to a watch channel
+// - One task periodically pings the agent for updates, sending them to watch channels
 // - One task responds to commands (to ensure that only one command is processed at a time)
-// - The handle can pull state updates and send commands
-// - When all handles have been dropped, the background tasks automatically terminate
+// - The handle can pull state updates and queue commands, waking the command task through a channel
+// - Queued commands are shared between the handles and the command task
+// - When all handles have been dropped, or the device is stopped and its queue drained, the background tasks terminate
+// - The handler waits on the raw state observed by the poller, while the state shown to handle holders also includes
+//   what the handler is in the middle of doing (for example, rebooting instead of off)
+// - The observed state, last target, and last action are persisted so that they survive controller restarts
+// - State changes, actions, and their steps are recorded in the device's history
+// - Before booting, the handler brings up the devices it requires through their own handles. Before turning off, it
+//   refuses or turns off the running devices which require it first, depending on its dependents policy. It only holds
+//   a weak reference to the devices, so that their handles don't keep its own tasks running
+// - Settings which can change on reload are shared between the handle and the command task. The command task holds
+//   the action lock while processing a command, so settings are only changed in between commands
 
-/// Frequency at which to ping the agent for state changes
+/// Default frequency at which to ping the agent for state changes
 const PING_INTERVAL: Duration = Duration::from_secs(5);
 
-/// Timeout when waiting for the device to complete an action
+/// Default timeout when waiting for the device to complete an action
 const ACTION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 
+/// Default timeout when waiting for the agent to install updates
+const MAINTENANCE_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+
+/// Default time to wait for the device to wake before resending the magic packet. Doubles after each packet.
+const WAKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default number of magic packets to send when waking the device
+const WAKE_ATTEMPTS: u32 = 5;
+
+/// Default number of times to reboot again when the device boots the wrong target
+const WRONG_TARGET_RETRIES: u32 = 2;
+
 /// Name of the GRUB environment variable to set with the desired menu entry.
 const GRUB_MENU_ENTRY_VAR: &str = "samwise_entry";
 
+/// Handles for every running device, which can change as the configuration is reloaded
+pub type Devices = Arc<RwLock<HashMap<DeviceId, Device>>>;
+
+/// Controller-wide services which every device uses
+#[derive(Clone)]
+pub struct Services {
+    pub waker: Waker,
+    pub tracker: ActionTracker,
+    pub store: StateStore,
+    /// Every running device, for acting on dependencies
+    pub devices: Devices,
+}
+
 #[derive(Clone)]
 pub struct Device {
     id: DeviceId,
+    agent_uri: String,
+    settings: Arc<RwLock<DeviceSettings>>,
+    action_lock: Arc<AsyncMutex<()>>,
+    agent: AgentConnection,
+    /// State including any transition in progress
     state_rx: watch::Receiver<State>,
-    action_tx: mpsc::Sender<Action>,
+    report_rx: watch::Receiver<AgentReport>,
+    queue: Arc<Mutex<ActionQueue>>,
+    wake_tx: mpsc::Sender<()>,
+    maintenance: LatestMaintenance,
+    store: DeviceStore,
+    history: History,
+    /// Closed once the action handler has exited
+    handler_rx: watch::Receiver<()>,
 }
 
-/// Current state of a device.
+/// Settings for a device which can be changed without restarting it.
 #[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DeviceSettings {
+    mac_address: MacAddr,
+    network_interface: String,
+    targets: HashMap<String, TargetConfiguration>,
+    grub_config: PathBuf,
+    update_target: Option<TargetId>,
+    default_target: Option<TargetId>,
+    action_policy: ActionPolicy,
+    wrong_target: WrongTargetPolicy,
+    wrong_target_retries: u32,
+    idle_policy: Option<IdlePolicy>,
+    /// Time zone for idle policy time windows
+    timezone: Tz,
+    requires: Vec<DeviceId>,
+    dependents: DependentsPolicy,
+    timing: TimingConfiguration,
+}
+
+/// How long to wait for a device, with configured overrides applied.
+#[derive(Debug, Clone, Copy)]
+struct Timing {
+    poll_interval: Duration,
+    boot_timeout: Duration,
+    shutdown_timeout: Duration,
+    suspend_timeout: Duration,
+    maintenance_timeout: Duration,
+    wake_interval: Duration,
+    wake_attempts: u32,
+}
+
+impl DeviceSettings {
+    pub fn new(config: &Configuration, device_config: &DeviceConfiguration) -> DeviceSettings {
+        DeviceSettings {
+            mac_address: device_config.mac_address(),
+            network_interface: device_config
+                .interface()
+                .unwrap_or_else(|| config.default_interface())
+                .to_string(),
+            targets: device_config.targets().clone(),
+            grub_config: config.tftp_directory().join(device_config.grub_config()),
+            update_target: device_config.update_target().map(TargetId::new),
+            default_target: device_config.default_target().map(TargetId::new),
+            action_policy: device_config.action_policy(),
+            wrong_target: device_config.wrong_target(),
+            wrong_target_retries: device_config
+                .wrong_target_retries()
+                .unwrap_or(WRONG_TARGET_RETRIES),
+            idle_policy: device_config.idle_policy().cloned(),
+            // Checked along with the rest of the configuration
+            timezone: config
+                .timezone()
+                .and_then(|timezone| schedule::parse_timezone(timezone).ok())
+                .unwrap_or(Tz::UTC),
+            requires: device_config.requires().collect(),
+            dependents: device_config.dependents(),
+            timing: device_config.timing().or(config.timing()),
+        }
+    }
+
+    /// Resolves a target ID or alias to the ID of a configured target.
+    fn resolve_target(&self, name: &str) -> Option<TargetId> {
+        if self.targets.contains_key(name) {
+            return Some(TargetId::new(name));
+        }
+        self.targets
+            .iter()
+            .find(|(_, target)| target.aliases().iter().any(|alias| alias == name))
+            .map(|(id, _)| TargetId::new(id))
+    }
+
+    /// Idle policy for the device while it's running `target`.
+    fn idle_policy(&self, target: &TargetId) -> Option<&IdlePolicy> {
+        self.targets
+            .get(target.as_string())
+            .and_then(TargetConfiguration::idle_policy)
+            .or(self.idle_policy.as_ref())
+    }
+
+    /// Timing configured for the device, using the overrides for `target` if given.
+    fn configured_timing(&self, target: Option<&TargetId>) -> TimingConfiguration {
+        match target.and_then(|target| self.targets.get(target.as_string())) {
+            Some(target) => target.timing().or(&self.timing),
+            None => self.timing.clone(),
+        }
+    }
+
+    /// Timing for the device, using the overrides for `target` if given.
+    fn timing(&self, target: Option<&TargetId>) -> Timing {
+        let timing = self.configured_timing(target);
+        Timing {
+            poll_interval: timing.poll_interval().unwrap_or(PING_INTERVAL),
+            boot_timeout: timing.boot_timeout().unwrap_or(ACTION_TIMEOUT),
+            shutdown_timeout: timing.shutdown_timeout().unwrap_or(ACTION_TIMEOUT),
+            suspend_timeout: timing.suspend_timeout().unwrap_or(ACTION_TIMEOUT),
+            maintenance_timeout: timing.maintenance_timeout().unwrap_or(MAINTENANCE_TIMEOUT),
+            wake_interval: timing.wake_interval().unwrap_or(WAKE_INTERVAL),
+            wake_attempts: timing.wake_attempts().unwrap_or(WAKE_ATTEMPTS).max(1),
+        }
+    }
+}
+
+/// Current state of a device.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum State {
     Unknown,
     Running(TargetId),
     Off,
+    /// Asleep, and will resume running the target when woken
+    Suspended(TargetId),
+    /// Not responding, even though the controller didn't turn it off
+    Unreachable,
+    /// Waking up from off. The target is unknown if the device was woken without configuring one.
+    Booting {
+        target: Option<TargetId>,
+    },
+    Rebooting {
+        from: TargetId,
+        to: TargetId,
+    },
+    ShuttingDown,
+    Suspending,
+}
+
+/// Tracks the state of a device as shown to handle holders: the state observed by the poller, unless the handler is
+/// in the middle of changing it.
+struct StateTracker {
+    observed: State,
+    transition: Option<State>,
+    /// Whether the controller turned the device off, as opposed to it becoming unreachable on its own
+    expect_off: bool,
+    /// Target the device was running when the controller suspended it, until it's seen running again
+    suspended: Option<TargetId>,
+    state_tx: watch::Sender<State>,
+    store: DeviceStore,
+    history: History,
+}
+
+impl StateTracker {
+    /// Records the raw state from pinging the agent, which is either `Running` or `Off`.
+    fn observe(&mut self, raw: &State) {
+        let previous = self.observed.clone();
+        self.observed = match raw {
+            State::Running(target) => {
+                if self.transition.is_none() {
+                    // Turned on outside the controller
+                    self.expect_off = false;
+                }
+                self.suspended = None;
+                State::Running(target.clone())
+            }
+            State::Off if self.suspended.is_some() => {
+                State::Suspended(self.suspended.clone().unwrap())
+            }
+            // Going down is expected during a reboot, and the transition is shown until it comes back
+            State::Off if self.expect_off || self.transition.is_some() => State::Off,
+            State::Off => match self.observed {
+                State::Running(_) | State::Unreachable => State::Unreachable,
+                _ => State::Off,
+            },
+            other => other.clone(),
+        };
+        if self.observed != previous {
+            self.history.record(EventKind::StateChanged {
+                state: self.observed.clone(),
+            });
+        }
+        self.publish();
+    }
+
+    fn begin(&mut self, transition: State) {
+        match transition {
+            State::ShuttingDown | State::Suspending => self.expect_off = true,
+            State::Booting { .. } | State::Rebooting { .. } => self.expect_off = false,
+            _ => (),
+        }
+        self.transition = Some(transition);
+        self.publish();
+    }
+
+    fn end(&mut self) {
+        self.transition = None;
+        self.publish();
+    }
+
+    /// Records that the controller suspended the device while it was running `target`.
+    fn suspend(&mut self, target: TargetId) {
+        if self.observed == State::Off {
+            self.observed = State::Suspended(target.clone());
+        }
+        self.suspended = Some(target);
+        self.publish();
+    }
+
+    fn publish(&self) {
+        let state = match self.transition {
+            Some(ref transition) => transition.clone(),
+            None => self.observed.clone(),
+        };
+        // If every receiver is gone, the device is stopping anyways
+        let _ = self.state_tx.broadcast(state);
+        self.persist();
+    }
+
+    /// Saves the observed state, so it can be restored if the controller restarts.
+    fn persist(&self) {
+        if self.observed == State::Unknown {
+            return;
+        }
+        self.store.update(|record| {
+            if record.state.as_ref() != Some(&self.observed) {
+                record.state = Some(self.observed.clone());
+                record.state_changed = Some(Utc::now());
+            }
+            if let State::Running(ref target) = self.observed {
+                record.last_target = Some(target.clone());
+            }
+            record.suspended = self.suspended.clone();
+            record.expect_off = self.expect_off;
+        });
+    }
+}
+
+type SharedStateTracker = Arc<Mutex<StateTracker>>;
+
+/// Shows a transitional state for a device until dropped.
+struct Transition(SharedStateTracker);
+
+impl Drop for Transition {
+    fn drop(&mut self) {
+        self.0
+            .lock()
+            .expect("Thread panicked with state mutex")
+            .end();
+    }
 }
 
 /// Command representing the desired state of a device.
@@ -55,6 +353,12 @@ pub enum Action {
     Suspend,
     ShutDown,
     Run(TargetId),
+    /// Run the device's default target
+    Boot,
+    DisplayOn,
+    DisplayOff,
+    /// Boot into the update target, install updates, and restore the previous state
+    Maintain,
 }
 
 impl fmt::Display for Action {
@@ -64,103 +368,593 @@ impl fmt::Display for Action {
             Action::Suspend => f.write_str("suspend"),
             Action::ShutDown => f.write_str("shut down"),
             Action::Run(target) => write!(f, "run {}", target),
+            Action::Boot => f.write_str("boot"),
+            Action::DisplayOn => f.write_str("turn display on"),
+            Action::DisplayOff => f.write_str("turn display off"),
+            Action::Maintain => f.write_str("maintain"),
+        }
+    }
+}
+
+/// Where an action was requested from, recorded in the device's history
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Source {
+    /// The HTTP API, from the given client address if known
+    Api(Option<SocketAddr>),
+    /// The device's agent, on behalf of a local user
+    Agent,
+    /// An action on a group the device is in
+    Group(String),
+    /// Booting a device which requires this one
+    Dependent(DeviceId),
+    /// Turning off a device which this one requires
+    Dependency(DeviceId),
+    /// A scheduled action, by the name of its schedule
+    Schedule(String),
+    /// The device's idle policy, with why it applied
+    Idle(String),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Api(Some(addr)) => write!(f, "API ({})", addr),
+            Source::Api(None) => f.write_str("API"),
+            Source::Agent => f.write_str("agent"),
+            Source::Group(name) => write!(f, "group {}", name),
+            Source::Dependent(id) => write!(f, "dependent device {}", id),
+            Source::Dependency(id) => write!(f, "dependency {}", id),
+            Source::Schedule(name) => write!(f, "schedule {}", name),
+            Source::Idle(reason) => write!(f, "idle policy ({})", reason),
         }
     }
 }
 
-/// Task which polls the agent service on a device to detect state changes.
+/// Task which polls the agent service on a device to detect state changes. The poll interval is looked up after each
+/// ping, since it can depend on the running target and change on reload.
 async fn state_poller(
     logger: Logger,
     mut agent: AgentConnection,
-    mut state_tx: watch::Sender<State>,
+    settings: Arc<RwLock<DeviceSettings>>,
+    state_tracker: SharedStateTracker,
+    mut observed_tx: watch::Sender<State>,
+    report_tx: watch::Sender<AgentReport>,
 ) {
-    // TODO: may want to make this configurable
-    let mut tick = time::interval(PING_INTERVAL);
-
     loop {
-        tokio::select! {
-            _ = state_tx.closed() => break,
-            _ = tick.tick() => {
-                let state = match agent.ping().await {
-                    AgentStatus::Active(target) => State::Running(target),
-                    AgentStatus::Inactive => State::Off,
-                };
+        // Stop as soon as the handler exits, so that a stopped device doesn't record any more states
+        let status = tokio::select! {
+            _ = observed_tx.closed() => break,
+            status = agent.ping() => status,
+        };
+        let (state, report) = match status {
+            AgentStatus::Active(target, report) => (State::Running(target), report),
+            AgentStatus::Inactive => (State::Off, AgentReport::default()),
+        };
 
-                // SendError from a watch channel also means it's closed
-                if state_tx.broadcast(state).is_err() {
-                    break;
-                }
-            }
+        let running_target = match state {
+            State::Running(ref target) => Some(target),
+            _ => None,
+        };
+        let interval = settings
+            .read()
+            .expect("Thread panicked with settings lock")
+            .timing(running_target)
+            .poll_interval;
+
+        state_tracker
+            .lock()
+            .expect("Thread panicked with state mutex")
+            .observe(&state);
+
+        // SendError from a watch channel also means it's closed
+        if observed_tx.broadcast(state).is_err() || report_tx.broadcast(report).is_err() {
+            break;
+        }
+
+        tokio::select! {
+            _ = observed_tx.closed() => break,
+            _ = time::delay_for(interval) => (),
         }
     }
     trace!(&logger, "Closing state poller");
 }
 
+/// Cancels an action when dropped, unless it has been cleared because the action finished
+struct CancelOnDrop {
+    device: Device,
+    id: Option<ActionId>,
+}
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            // The action may have finished in the meantime
+            let _ = self.device.cancel(Some(id));
+        }
+    }
+}
+
+/// Error when a device boots a different target than the one requested
+#[derive(Debug)]
+pub struct WrongTarget {
+    pub requested: TargetId,
+    pub running: TargetId,
+}
+
+impl fmt::Display for WrongTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Expected device to boot {}, but it's running {}",
+            self.requested, self.running
+        )
+    }
+}
+
+impl std::error::Error for WrongTarget {}
+
 struct Handler {
     id: DeviceId,
     logger: Logger,
     agent: AgentConnection,
 
-    mac_address: MacAddr,
-    network_interface: String,
     waker: Waker,
-    targets: HashMap<String, TargetConfiguration>,
-    grub_config: PathBuf,
+    settings: Arc<RwLock<DeviceSettings>>,
+    action_lock: Arc<AsyncMutex<()>>,
+    maintenance: LatestMaintenance,
+    state_tracker: SharedStateTracker,
 
-    state_rx: watch::Receiver<State>,
-    action_rx: mpsc::Receiver<Action>,
+    /// Raw state observed by the poller, without transitions
+    observed_rx: watch::Receiver<State>,
+    queue: Arc<Mutex<ActionQueue>>,
+    wake_rx: mpsc::Receiver<()>,
+
+    /// Contents of the GRUB config file before the current action changed it, kept until the device boots with the
+    /// new contents so that they can be restored if the action is cancelled
+    grub_backup: Option<Vec<u8>>,
+    /// Last target the device booted into on request, for the fallback wrong-target policy
+    known_good: Option<TargetId>,
+    store: DeviceStore,
+    history: History,
+    devices: Weak<RwLock<HashMap<DeviceId, Device>>>,
 }
 
 impl Handler {
     async fn process(&mut self) -> Result<()> {
-        while let Some(action) = self.action_rx.recv().await {
-            let result = match action {
-                Action::Run(ref target) => self.handle_run(target).await,
-                Action::Reboot => self.handle_reboot().await,
-                Action::Suspend => self.handle_suspend().await,
-                Action::ShutDown => self.handle_shutdown().await,
-            };
+        while self.wake_rx.recv().await.is_some() {
+            while self.process_next().await {}
+            if self.queue().is_stopped() {
+                break;
+            }
+        }
+
+        trace!(&self.logger, "Closing action handler");
+        Ok(())
+    }
 
-            if let Err(error) = result {
+    /// Handles the next queued action, returning false if there was none.
+    async fn process_next(&mut self) -> bool {
+        // Take the action lock before starting the action, so that an idle lock and empty queue mean the device is idle
+        let action_lock = self.action_lock.clone();
+        let _guard = action_lock.lock().await;
+
+        let (id, action, cancel_rx) = match self.queue().start() {
+            Some(next) => next,
+            None => return false,
+        };
+        trace!(&self.logger, "Starting action"; "action" => %action, "id" => %id);
+        self.history.record(EventKind::ActionStarted {
+            id,
+            action: action.to_string(),
+        });
+        self.store.update(|record| {
+            record.last_action = Some(action.to_string());
+            record.last_action_started = Some(Utc::now());
+            record.last_action_finished = None;
+            record.last_error = None;
+        });
+
+        self.grub_backup = None;
+        let handled = tokio::select! {
+            result = self.handle(&action) => Some(result),
+            Ok(()) = cancel_rx => None,
+        };
+        let result = match handled {
+            Some(result) => result,
+            None => {
+                info!(&self.logger, "Cancelled {} action", action; "id" => %id);
+                self.restore_grub().await;
+                Err(Cancelled.into())
+            }
+        };
+
+        if let Err(ref error) = result {
+            if !error.is::<Cancelled>() {
                 error!(
                     &self.logger,
                     "Handling {} action failed: {:?}", action, error
                 );
             }
         }
+        self.store.update(|record| {
+            record.last_action_finished = Some(Utc::now());
+            record.last_error = result.as_ref().err().map(|error| format!("{:#}", error));
+        });
+        let (status, outcome) = queue::outcome(&result);
+        self.history.record(EventKind::ActionFinished {
+            id,
+            action: action.to_string(),
+            status,
+            error: outcome.err(),
+        });
+        self.queue().finish(&result);
+        true
+    }
+
+    async fn handle(&mut self, action: &Action) -> Result<()> {
+        match action {
+            Action::Run(_) | Action::Boot | Action::Maintain => self.start_dependencies().await?,
+            Action::Suspend | Action::ShutDown => self.stop_dependents(action).await?,
+            _ => (),
+        }
+
+        match action {
+            Action::Run(ref target) => self.handle_run(target).await,
+            Action::Boot => self.handle_boot().await,
+            Action::Reboot => self.handle_reboot().await,
+            Action::Suspend => self.handle_suspend().await,
+            Action::ShutDown => self.handle_shutdown().await,
+            Action::DisplayOn => self.handle_display(true).await,
+            Action::DisplayOff => self.handle_display(false).await,
+            Action::Maintain => self.handle_maintenance().await,
+        }
+    }
+
+    fn queue(&self) -> MutexGuard<'_, ActionQueue> {
+        self.queue.lock().expect("Thread panicked with queue mutex")
+    }
+
+    /// Handles for the running devices matching `filter`, other than this one.
+    fn other_devices<F: Fn(&Device) -> bool>(&self, filter: F) -> Vec<Device> {
+        let devices = match self.devices.upgrade() {
+            Some(devices) => devices,
+            None => return Vec::new(),
+        };
+        let devices = devices.read().expect("Thread panicked with devices lock");
+        devices
+            .values()
+            .filter(|device| device.id() != &self.id && filter(device))
+            .cloned()
+            .collect()
+    }
+
+    /// Has each of `devices` perform `action` on behalf of this device, waiting for all of them. Fails without acting if
+    /// one of them is busy with an action that's waiting for this device, since neither could ever finish.
+    async fn perform_on_others(
+        &mut self,
+        devices: Vec<Device>,
+        action: &Action,
+        source: Source,
+    ) -> Vec<(DeviceId, Result<()>)> {
+        self.queue()
+            .wait_on(devices.iter().map(|device| device.id().clone()).collect());
+        let results = futures::future::join_all(devices.into_iter().map(|mut device| {
+            let action = action.clone();
+            let source = source.clone();
+            let waiting = device.queue().is_waiting_on(&self.id);
+            async move {
+                let result = if waiting {
+                    Err(anyhow!("Device {} is waiting for this device", device.id()))
+                } else {
+                    device.perform_for(action, source).await
+                };
+                (device.id().clone(), result)
+            }
+        }))
+        .await;
+        self.queue().wait_on(Vec::new());
+        results
+    }
+
+    /// Boots the devices this device requires which aren't already running, waiting for all of them.
+    async fn start_dependencies(&mut self) -> Result<()> {
+        let requires = self.settings().requires;
+        let stopped = self.other_devices(|device| {
+            requires.contains(device.id()) && !matches!(device.latest_state(), State::Running(_))
+        });
+        if stopped.is_empty() {
+            return Ok(());
+        }
 
-        trace!(&self.logger, "Closing action handler");
+        let names: Vec<String> = stopped
+            .iter()
+            .map(|device| device.id().to_string())
+            .collect();
+        self.step(format!("Starting required devices: {}", names.join(", ")));
+        let source = Source::Dependent(self.id.clone());
+        let results = self.perform_on_others(stopped, &Action::Boot, source).await;
+        for (id, result) in results {
+            result.with_context(|| format!("Could not start required device {}", id))?;
+        }
         Ok(())
     }
 
+    /// Checks for running devices which require this one before it's turned off by `action`. Depending on the
+    /// dependents policy, either fails or performs the same action on those devices first.
+    async fn stop_dependents(&mut self, action: &Action) -> Result<()> {
+        let running = self.other_devices(|device| {
+            let requires_this = device
+                .settings
+                .read()
+                .expect("Thread panicked with settings lock")
+                .requires
+                .contains(&self.id);
+            let running = matches!(
+                device.latest_state(),
+                State::Running(_) | State::Booting { .. } | State::Rebooting { .. }
+            );
+            requires_this && running
+        });
+        if running.is_empty() {
+            return Ok(());
+        }
+
+        let names: Vec<String> = running
+            .iter()
+            .map(|device| device.id().to_string())
+            .collect();
+        match self.settings().dependents {
+            DependentsPolicy::Refuse => bail!("Still required by {}", names.join(", ")),
+            DependentsPolicy::Cascade => {
+                self.step(format!("Stopping dependent devices: {}", names.join(", ")));
+                let source = Source::Dependency(self.id.clone());
+                let results = self.perform_on_others(running, action, source).await;
+                for (id, result) in results {
+                    result.with_context(|| format!("Could not stop dependent device {}", id))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Records the step the current action is on, for clients following its progress.
+    fn step<S: Into<String>>(&self, step: S) {
+        let step = step.into();
+        let queue = self.queue();
+        if let Some(id) = queue.current() {
+            self.history.record(EventKind::Step {
+                id,
+                step: step.clone(),
+            });
+        }
+        queue.step(step);
+    }
+
+    /// Current settings for the device. These can only change in between actions.
+    fn settings(&self) -> DeviceSettings {
+        self.settings
+            .read()
+            .expect("Thread panicked with settings lock")
+            .clone()
+    }
+
+    /// Shows `state` as the device's state until the returned guard is dropped.
+    fn transition(&self, state: State) -> Transition {
+        self.state_tracker
+            .lock()
+            .expect("Thread panicked with state mutex")
+            .begin(state);
+        Transition(self.state_tracker.clone())
+    }
+
+    /// Target the device was running when it was suspended, if it's currently suspended.
+    fn suspended_target(&self) -> Option<TargetId> {
+        self.state_tracker
+            .lock()
+            .expect("Thread panicked with state mutex")
+            .suspended
+            .clone()
+    }
+
+    /// Current timing for the device, using the overrides for `target` if given. With adaptive timeouts, timeouts
+    /// which aren't configured are derived from how long the target has taken before, once there are enough
+    /// measurements.
+    fn timing(&self, target: Option<&TargetId>) -> Timing {
+        let settings = self.settings();
+        let mut timing = settings.timing(target);
+        let configured = settings.configured_timing(target);
+        let target = match target {
+            Some(target) if configured.adaptive_timeouts() == Some(true) => target,
+            _ => return timing,
+        };
+
+        let stats = self.history.stats();
+        let learned = |phase| stats::learned_timeout(&stats, phase, target);
+        if configured.boot_timeout().is_none() {
+            // Reboots take longer than booting from off, so both are covered once reboots have been measured too
+            if let Some(boot) = learned(Phase::Boot) {
+                timing.boot_timeout = boot.max(learned(Phase::Reboot).unwrap_or(boot));
+            }
+        }
+        if configured.shutdown_timeout().is_none() {
+            if let Some(shutdown) = learned(Phase::Shutdown) {
+                timing.shutdown_timeout = shutdown;
+            }
+        }
+        if configured.suspend_timeout().is_none() {
+            if let Some(suspend) = learned(Phase::Suspend) {
+                timing.suspend_timeout = suspend;
+            }
+        }
+        timing
+    }
+
+    /// Records how long `phase` took for `target`, if it started at `started`.
+    fn measure(&self, phase: Phase, target: &TargetId, started: Instant) {
+        self.history.record(EventKind::Measured {
+            phase,
+            target: target.clone(),
+            seconds: started.elapsed().as_secs_f64(),
+        });
+    }
+
     // When handling an action, ping initially to make sure we're acting on up-to-date state. When
-    // looping to wait for an action to finish after that, always use self.state_rx to avoid spamming
+    // looping to wait for an action to finish after that, always use self.observed_rx to avoid spamming
     // the agent with pings.
 
-    /// Handles a `Run` action.
-    async fn handle_run(&mut self, target: &TargetId) -> Result<()> {
-        debug!(&self.logger, "Told to run {}", target);
+    /// Handles a `Run` action. The target may be given by alias. If the device boots the wrong target, the device's
+    /// wrong-target policy is applied.
+    async fn handle_run(&mut self, requested: &TargetId) -> Result<()> {
+        debug!(&self.logger, "Told to run {}", requested);
+        let settings = self.settings();
+        let target = &match settings.resolve_target(requested.as_string()) {
+            Some(target) => target,
+            None => bail!("No such target `{}`", requested),
+        };
+
+        let mut result = self.run_target(target).await;
+        let mut retries = 0;
+        loop {
+            let running = match result {
+                Err(ref error) => match error.downcast_ref::<WrongTarget>() {
+                    Some(wrong) => wrong.running.clone(),
+                    None => return result,
+                },
+                Ok(()) => return result,
+            };
+
+            match settings.wrong_target {
+                WrongTargetPolicy::Retry if retries < settings.wrong_target_retries => {
+                    retries += 1;
+                    warn!(
+                        &self.logger,
+                        "Booted {} instead of {} - will reboot again", running, target;
+                        "retry" => retries
+                    );
+                    result = self.switch_target(&running, target).await;
+                }
+                WrongTargetPolicy::Fallback => {
+                    return self.fall_back(&running, result.unwrap_err()).await
+                }
+                _ => return result,
+            }
+        }
+    }
+
+    /// Reboots the device into the last target it successfully booted, after it booted `running` by mistake. Since
+    /// the requested target still isn't running, this always fails with `error`.
+    async fn fall_back(&mut self, running: &TargetId, error: anyhow::Error) -> Result<()> {
+        let fallback = match self
+            .known_good
+            .clone()
+            .or_else(|| self.settings().default_target)
+        {
+            Some(fallback) => fallback,
+            None => return Err(error.context("No known-good target to fall back to")),
+        };
+
+        if &fallback != running {
+            warn!(
+                &self.logger,
+                "Booted {} by mistake - will fall back to {}", running, fallback
+            );
+            if let Err(fallback_error) = self.switch_target(running, &fallback).await {
+                return Err(error.context(format!(
+                    "Falling back to {} also failed: {:#}",
+                    fallback, fallback_error
+                )));
+            }
+        }
+        Err(error.context(format!("Fell back to {}", fallback)))
+    }
+
+    /// Gets the device running `target`, however it's currently running.
+    async fn run_target(&mut self, target: &TargetId) -> Result<()> {
         match self.agent.ping().await {
-            AgentStatus::Active(ref active_target) => {
+            AgentStatus::Active(ref active_target, _) => {
                 if active_target == target {
                     debug!(&self.logger, "Already running {}", target);
+                    self.known_good = Some(target.clone());
                     Ok(())
                 } else {
                     debug!(
                         &self.logger,
                         "Running {}, but {} requested - will reboot", active_target, target
                     );
-                    self.configure(target).await?;
-                    self.agent.reboot().await?;
-                    self.await_running_target(target).await
+                    self.switch_target(active_target, target).await
                 }
             }
-            AgentStatus::Inactive => {
-                debug!(&self.logger, "Not running - will boot");
-                self.configure(target).await?;
-                self.boot().await?;
-                self.await_running_target(target).await
-            }
+            AgentStatus::Inactive => match self.suspended_target() {
+                Some(ref suspended) if suspended == target => {
+                    debug!(&self.logger, "Suspended in {} - will wake", target);
+                    // Resuming doesn't go through GRUB, so there's nothing to configure
+                    self.wake_suspended(target).await
+                }
+                Some(suspended) => {
+                    debug!(
+                        &self.logger,
+                        "Suspended in {}, but {} requested - will wake and reboot",
+                        suspended,
+                        target
+                    );
+                    self.wake_suspended(&suspended).await?;
+                    self.switch_target(&suspended, target).await
+                }
+                None => self.cold_boot(target).await,
+            },
+        }
+    }
+
+    /// Reboots the device from running `from` into `to`.
+    async fn switch_target(&mut self, from: &TargetId, to: &TargetId) -> Result<()> {
+        let _transition = self.transition(State::Rebooting {
+            from: from.clone(),
+            to: to.clone(),
+        });
+        self.configure(to).await?;
+        self.step("Rebooting");
+        let started = Instant::now();
+        self.agent.reboot().await?;
+        let went_down = self
+            .await_running_target(to, self.timing(Some(to)).boot_timeout, Some(from))
+            .await?;
+        // If the device came back before it was seen going down, the reboot may have started before `started`
+        if went_down {
+            self.measure(Phase::Reboot, to, started);
+        }
+        Ok(())
+    }
+
+    /// Wakes the device from suspend, where it resumes running `target` without going through GRUB.
+    async fn wake_suspended(&mut self, target: &TargetId) -> Result<()> {
+        let _transition = self.transition(State::Booting {
+            target: Some(target.clone()),
+        });
+        let started = Instant::now();
+        self.wake_and_await(Some(target)).await?;
+        self.measure(Phase::Resume, target, started);
+        Ok(())
+    }
+
+    /// Boots the device into `target` from off.
+    async fn cold_boot(&mut self, target: &TargetId) -> Result<()> {
+        debug!(&self.logger, "Not running - will boot {}", target);
+        let _transition = self.transition(State::Booting {
+            target: Some(target.clone()),
+        });
+        self.configure(target).await?;
+        let started = Instant::now();
+        self.wake_and_await(Some(target)).await?;
+        self.measure(Phase::Boot, target, started);
+        Ok(())
+    }
+
+    /// Handles a `Boot` action.
+    async fn handle_boot(&mut self) -> Result<()> {
+        debug!(&self.logger, "Told to boot the default target");
+        match self.settings().default_target {
+            Some(target) => self.handle_run(&target).await,
+            None => bail!("No default target configured"),
         }
     }
 
@@ -168,16 +962,47 @@ impl Handler {
     async fn handle_reboot(&mut self) -> Result<()> {
         debug!(&self.logger, "Told to reboot");
         match self.agent.ping().await {
-            AgentStatus::Active(target) => {
+            AgentStatus::Active(target, _) => {
                 debug!(&self.logger, "Rebooting to {}", target);
+                let _transition = self.transition(State::Rebooting {
+                    from: target.clone(),
+                    to: target.clone(),
+                });
+                self.step("Rebooting");
+                let started = Instant::now();
                 self.agent.reboot().await?;
-                self.await_running_target(&target).await
+                let went_down = self
+                    .await_running_target(
+                        &target,
+                        self.timing(Some(&target)).boot_timeout,
+                        Some(&target),
+                    )
+                    .await?;
+                if went_down {
+                    self.measure(Phase::Reboot, &target, started);
+                }
+                Ok(())
             }
             AgentStatus::Inactive => {
+                if let Some(target) = self.suspended_target() {
+                    debug!(
+                        &self.logger,
+                        "Suspended in {} - will wake and reboot", target
+                    );
+                    self.wake_suspended(&target).await?;
+                    return self.switch_target(&target, &target).await;
+                }
+
+                // Boot what the device was running last, even if that was before the controller restarted
+                let last_target = self.store.get().last_target;
+                if let Some(target) = last_target.or(self.settings().default_target) {
+                    return self.cold_boot(&target).await;
+                }
+
                 debug!(&self.logger, "Not running - will boot");
-                self.boot().await?;
+                let _transition = self.transition(State::Booting { target: None });
                 // Can't wait for a specific target since we don't know what was running previously
-                self.await_running().await
+                self.wake_and_await(None).await
             }
         }
     }
@@ -186,10 +1011,20 @@ impl Handler {
     async fn handle_suspend(&mut self) -> Result<()> {
         debug!(&self.logger, "Told to suspend");
         match self.agent.ping().await {
-            AgentStatus::Active(target) => {
+            AgentStatus::Active(target, _) => {
                 debug!(&self.logger, "Running {} - will suspend", target);
+                let _transition = self.transition(State::Suspending);
+                self.step("Suspending");
+                let started = Instant::now();
                 self.agent.suspend().await?;
-                self.await_off().await
+                self.await_off(self.timing(Some(&target)).suspend_timeout)
+                    .await?;
+                self.measure(Phase::Suspend, &target, started);
+                self.state_tracker
+                    .lock()
+                    .expect("Thread panicked with state mutex")
+                    .suspend(target);
+                Ok(())
             }
             AgentStatus::Inactive => {
                 debug!(&self.logger, "Already off or suspended");
@@ -202,10 +1037,16 @@ impl Handler {
     async fn handle_shutdown(&mut self) -> Result<()> {
         debug!(&self.logger, "Told to shut down");
         match self.agent.ping().await {
-            AgentStatus::Active(target) => {
+            AgentStatus::Active(target, _) => {
                 debug!(&self.logger, "Running {} - will shut down", target);
+                let _transition = self.transition(State::ShuttingDown);
+                self.step("Shutting down");
+                let started = Instant::now();
                 self.agent.shut_down().await?;
-                self.await_off().await
+                self.await_off(self.timing(Some(&target)).shutdown_timeout)
+                    .await?;
+                self.measure(Phase::Shutdown, &target, started);
+                Ok(())
             }
             AgentStatus::Inactive => {
                 debug!(&self.logger, "Already off or suspended");
@@ -214,21 +1055,141 @@ impl Handler {
         }
     }
 
+    /// Handles a `DisplayOn` or `DisplayOff` action. The device must already be running, since waking it just to
+    /// change the display would be surprising.
+    async fn handle_display(&mut self, on: bool) -> Result<()> {
+        debug!(
+            &self.logger,
+            "Told to turn display {}",
+            if on { "on" } else { "off" }
+        );
+        match self.agent.ping().await {
+            AgentStatus::Active(_, _) => {
+                if on {
+                    self.agent.display_on().await
+                } else {
+                    self.agent.display_off().await
+                }
+            }
+            AgentStatus::Inactive => bail!("Device is not running"),
+        }
+    }
+
+    /// Handles a `Maintain` action.
+    async fn handle_maintenance(&mut self) -> Result<()> {
+        debug!(&self.logger, "Told to perform maintenance");
+        let update_target = match self.settings().update_target {
+            Some(target) => target,
+            None => bail!("No update target configured"),
+        };
+
+        let log = MaintenanceLog::start(self.maintenance.clone());
+        let result = self.perform_maintenance(&update_target, &log).await;
+        log.finish(result.as_ref().err().map(|error| format!("{:#}", error)));
+        result
+    }
+
+    async fn perform_maintenance(
+        &mut self,
+        update_target: &TargetId,
+        log: &MaintenanceLog,
+    ) -> Result<()> {
+        let previous = match self.agent.ping().await {
+            AgentStatus::Active(target, _) => {
+                log.step(format!("Device was running {}", target));
+                State::Running(target)
+            }
+            AgentStatus::Inactive => match self.suspended_target() {
+                Some(target) => {
+                    log.step(format!("Device was suspended in {}", target));
+                    State::Suspended(target)
+                }
+                None => {
+                    log.step("Device was off");
+                    State::Off
+                }
+            },
+        };
+
+        log.step(format!("Booting {}", update_target));
+        self.handle_run(update_target).await?;
+
+        log.step("Installing updates");
+        self.step("Installing updates");
+        let timeout = self.timing(Some(update_target)).maintenance_timeout;
+        let result = time::timeout(timeout, self.agent.update())
+            .await
+            .with_context(|| {
+                format!(
+                    "Timed out after {}s waiting for updates to install",
+                    timeout.as_secs()
+                )
+            })??;
+        if !result.success {
+            bail!("Installing updates failed:\n{}", result.output);
+        }
+
+        if result.reboot_required {
+            log.step("Rebooting to finish installing updates");
+            let _transition = self.transition(State::Rebooting {
+                from: update_target.clone(),
+                to: update_target.clone(),
+            });
+            self.step("Rebooting");
+            self.agent.reboot().await?;
+            self.await_running_target(
+                update_target,
+                self.timing(Some(update_target)).boot_timeout,
+                Some(update_target),
+            )
+            .await?;
+        }
+
+        match previous {
+            State::Running(ref target) if target != update_target => {
+                log.step(format!("Booting {} to restore previous state", target));
+                self.handle_run(target).await?;
+            }
+            State::Suspended(ref target) => {
+                if target != update_target {
+                    log.step(format!("Booting {} to restore previous state", target));
+                    self.handle_run(target).await?;
+                }
+                log.step("Suspending to restore previous state");
+                self.handle_suspend().await?;
+            }
+            State::Off => {
+                log.step("Shutting down to restore previous state");
+                self.handle_shutdown().await?;
+            }
+            _ => (),
+        }
+
+        log.step("Maintenance complete");
+        Ok(())
+    }
+
     /// Configure the device to load a specific target on next boot
     async fn configure(&mut self, target: &TargetId) -> Result<()> {
-        match self.targets.get(target.as_string()) {
+        self.step(format!("Configuring GRUB for {}", target));
+        let settings = self.settings();
+        match settings.targets.get(target.as_string()) {
             Some(target) => {
+                if self.grub_backup.is_none() {
+                    self.grub_backup = fs::read(&settings.grub_config).await.ok();
+                }
+
                 // Expect the file to already exist so that we don't have to worry about TFTP-server-specific permissions issues. For example,
                 // dnsmasq in secure mode requires that it own all TFTP files.
                 let mut file = OpenOptions::new()
                     .write(true)
                     .truncate(true)
-                    .open(&self.grub_config)
+                    .open(&settings.grub_config)
                     .await
                     .with_context(|| {
                         format!(
                             "Could not open GRUB config file `{}`",
-                            self.grub_config.display()
+                            settings.grub_config.display()
                         )
                     })?;
 
@@ -241,7 +1202,7 @@ impl Handler {
                 file.write_all(contents.as_bytes()).await.with_context(|| {
                     format!(
                         "Could not write to GRUB config file `{}`",
-                        self.grub_config.display()
+                        settings.grub_config.display()
                     )
                 })?;
                 Ok(())
@@ -250,52 +1211,157 @@ impl Handler {
         }
     }
 
+    /// Restores the GRUB config file to what it was before the current action changed it, if the device hasn't booted
+    /// with the new contents yet.
+    async fn restore_grub(&mut self) {
+        let contents = match self.grub_backup.take() {
+            Some(contents) => contents,
+            None => return,
+        };
+        let grub_config = self.settings().grub_config;
+        self.step("Restoring GRUB config");
+        if let Err(error) = fs::write(&grub_config, contents).await {
+            warn!(
+                &self.logger,
+                "Could not restore GRUB config file `{}`: {}",
+                grub_config.display(),
+                error
+            );
+        }
+    }
+
     /// Boot the device via Wake-on-LAN.
     async fn boot(&mut self) -> Result<()> {
+        self.step("Sending Wake-on-LAN packet");
+        let settings = self.settings();
         self.waker
-            .wake(self.network_interface.clone(), self.mac_address)
+            .wake(settings.network_interface, settings.mac_address)
             .await
             .with_context(|| format!("Could not wake {}", self.id))
     }
 
-    /// Waits for the device to be running a particular target.
-    async fn await_running_target(&self, target: &TargetId) -> Result<()> {
-        self.await_state(|state| match state {
-            State::Running(ref current_target) => current_target == target,
-            _ => false,
-        })
-        .await
+    /// Wakes the device and waits for it to be running `target`, or any target if there is none. The magic packet is
+    /// resent with exponential backoff until the device answers or the configured number of packets have been sent.
+    async fn wake_and_await(&mut self, target: Option<&TargetId>) -> Result<()> {
+        let timing = self.timing(target);
+        let deadline = Instant::now() + timing.boot_timeout;
+        let mut interval = timing.wake_interval;
+        let mut packets = 0;
+        loop {
+            self.boot().await?;
+            packets += 1;
+            self.queue().wake_packets(packets);
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let last = packets >= timing.wake_attempts;
+            let wait = if last {
+                remaining
+            } else {
+                interval.min(remaining)
+            };
+            let result = match target {
+                Some(target) => self
+                    .await_running_target(target, wait, None)
+                    .await
+                    .map(|_| ()),
+                None => self.await_running(wait).await,
+            };
+
+            match result {
+                Err(ref error) if !last && error.is::<Elapsed>() && Instant::now() < deadline => {
+                    debug!(
+                        &self.logger,
+                        "No response after {} Wake-on-LAN packets, sending another", packets
+                    );
+                    interval *= 2;
+                }
+                Ok(()) => {
+                    debug!(
+                        &self.logger,
+                        "Woke up after {} Wake-on-LAN packets", packets
+                    );
+                    return Ok(());
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    /// Waits for the device to be running a particular target, failing with `WrongTarget` as soon as it's seen
+    /// running another one. If the device was just told to reboot from `stale`, reports of `stale` are ignored until
+    /// the device is seen going down, since they may be from before the reboot. Returns whether the device was seen
+    /// going down.
+    async fn await_running_target(
+        &mut self,
+        target: &TargetId,
+        timeout: Duration,
+        stale: Option<&TargetId>,
+    ) -> Result<bool> {
+        self.step(format!("Waiting for agent to report {}", target));
+        let mut went_down = false;
+        let state = self
+            .await_state(timeout, |state| match state {
+                // Until the device has gone down, a report of the stale target is from before the reboot, even if
+                // it's the target being waited for
+                State::Running(ref current_target) => went_down || Some(current_target) != stale,
+                State::Off => {
+                    went_down = true;
+                    false
+                }
+                _ => false,
+            })
+            .await?;
+
+        match state {
+            State::Running(running) if &running != target => Err(WrongTarget {
+                requested: target.clone(),
+                running,
+            }
+            .into()),
+            _ => {
+                // The device has booted with the new GRUB config, so there's nothing to restore
+                self.grub_backup = None;
+                self.known_good = Some(target.clone());
+                Ok(went_down)
+            }
+        }
     }
 
     /// Waits for the device to be in any running state.
-    async fn await_running(&self) -> Result<()> {
-        self.await_state(|state| matches!(state, State::Running(_)))
-            .await
+    async fn await_running(&self, timeout: Duration) -> Result<()> {
+        self.step("Waiting for agent");
+        self.await_state(timeout, |state| matches!(state, State::Running(_)))
+            .await?;
+        Ok(())
     }
 
     /// Waits for the device to be off or suspended.
-    async fn await_off(&self) -> Result<()> {
-        self.await_state(|state| state == &State::Off).await
+    async fn await_off(&self, timeout: Duration) -> Result<()> {
+        self.step("Waiting for device to turn off");
+        self.await_state(timeout, |state| state == &State::Off)
+            .await?;
+        Ok(())
     }
 
-    /// Waits for the device to be in a given state. Fails if the state-polling task exits in the
-    /// background or the device takes too long to reach the desired state.
-    async fn await_state<F>(&self, pred: F) -> Result<()>
+    /// Waits for the device to be in a given state, returning the state that matched. Fails if the state-polling task
+    /// exits in the background or the device takes longer than `timeout` to reach the desired state.
+    async fn await_state<F>(&self, timeout: Duration, mut pred: F) -> Result<State>
     where
-        F: Fn(&State) -> bool,
+        F: FnMut(&State) -> bool,
     {
-        let mut state_rx = self.state_rx.clone();
-        time::timeout(ACTION_TIMEOUT, async {
+        let mut state_rx = self.observed_rx.clone();
+        time::timeout(timeout, async {
             // Check if the device is already in the desired state before looping, since recv() will
             // only yield any given state change once
-            if pred(&*state_rx.borrow()) {
-                Ok(())
+            let current_state = state_rx.borrow().clone();
+            if pred(&current_state) {
+                Ok(current_state)
             } else {
                 loop {
                     match state_rx.recv().await {
-                        Some(ref current_state) => {
-                            if pred(current_state) {
-                                break Ok(());
+                        Some(current_state) => {
+                            if pred(&current_state) {
+                                break Ok(current_state);
                             }
                         }
                         // If the state update channel closed, we'll never get notified for the desired state
@@ -305,7 +1371,12 @@ impl Handler {
             }
         })
         .await
-        .context("Timed out waiting for device to reach desired state")?
+        .with_context(|| {
+            format!(
+                "Timed out after {}s waiting for device to reach desired state",
+                timeout.as_secs()
+            )
+        })?
     }
 }
 
@@ -313,7 +1384,7 @@ impl Device {
     pub fn start(
         id: DeviceId,
         config: &Configuration,
-        waker: Waker,
+        services: &Services,
         logger: &Logger,
     ) -> Result<Device> {
         let device_config = match config.device_config(&id) {
@@ -325,39 +1396,86 @@ impl Device {
         let agent = AgentConnection::new(device_config.agent().to_string(), &logger)
             .with_context(|| format!("Bad agent for device {}", id))?;
 
-        let (state_tx, state_rx) = watch::channel(State::Unknown);
-        let (action_tx, action_rx) = mpsc::channel(1);
+        // Show the state from before the controller restarted until the poller sees the current state. The handler
+        // only waits on freshly observed states, so it starts out unknown.
+        let store = services.store.open(&id);
+        let history = services.store.history(&id);
+        let record = store.get();
+        let restored = record.state.clone().unwrap_or(State::Unknown);
+        let (state_tx, state_rx) = watch::channel(restored.clone());
+        let (observed_tx, observed_rx) = watch::channel(State::Unknown);
+        let state_tracker = Arc::new(Mutex::new(StateTracker {
+            observed: restored,
+            transition: None,
+            expect_off: record.expect_off,
+            suspended: record.suspended.clone(),
+            state_tx,
+            store: store.clone(),
+            history: history.clone(),
+        }));
+        let (report_tx, report_rx) = watch::channel(AgentReport::default());
+        // Only one wakeup needs to be pending, since the handler drains the queue each time
+        let (wake_tx, wake_rx) = mpsc::channel(1);
+        let queue = Arc::new(Mutex::new(ActionQueue::new(
+            id.clone(),
+            services.tracker.clone(),
+        )));
+        let maintenance: LatestMaintenance = Arc::new(Mutex::new(None));
+        let settings = Arc::new(RwLock::new(DeviceSettings::new(config, device_config)));
+        let action_lock = Arc::new(AsyncMutex::new(()));
 
         let state_logger = logger.clone();
         let state_agent = agent.clone();
-        let _ = tokio::spawn(state_poller(state_logger, state_agent, state_tx));
+        tokio::spawn(state_poller(
+            state_logger,
+            state_agent,
+            settings.clone(),
+            state_tracker.clone(),
+            observed_tx,
+            report_tx,
+        ));
 
         let mut handler = Handler {
             id: id.clone(),
             logger,
-            agent,
-            mac_address: device_config.mac_address(),
-            network_interface: device_config
-                .interface()
-                .unwrap_or_else(|| config.default_interface())
-                .to_string(),
-            waker,
-            targets: device_config.targets().clone(),
-            grub_config: config.tftp_directory().join(device_config.grub_config()),
-            state_rx: state_rx.clone(),
-            action_rx,
+            agent: agent.clone(),
+            waker: services.waker.clone(),
+            settings: settings.clone(),
+            action_lock: action_lock.clone(),
+            maintenance: maintenance.clone(),
+            state_tracker,
+            observed_rx,
+            queue: queue.clone(),
+            wake_rx,
+            grub_backup: None,
+            known_good: record.last_target,
+            store: store.clone(),
+            history: history.clone(),
+            devices: Arc::downgrade(&services.devices),
         };
 
-        let _ = tokio::spawn(async move {
+        let (handler_tx, handler_rx) = watch::channel(());
+        tokio::spawn(async move {
             if let Err(e) = handler.process().await {
                 error!(handler.logger, "Handler failed: {}", e);
             }
+            drop(handler_tx);
         });
 
         Ok(Device {
             id,
+            agent_uri: device_config.agent().to_string(),
+            settings,
+            action_lock,
+            agent,
             state_rx,
-            action_tx,
+            report_rx,
+            queue,
+            wake_tx,
+            maintenance,
+            store,
+            history,
+            handler_rx,
         })
     }
 
@@ -365,12 +1483,242 @@ impl Device {
         &self.id
     }
 
-    /// Tells the device to perform an action. If the device is busy, this will fail immediately.
-    pub async fn action(&mut self, action: Action) -> Result<()> {
-        self.action_tx
-            .try_send(action)
-            .with_context(|| format!("Could not send action to device {:?}", self.id))?;
-        Ok(())
+    /// URI of the agent this device was started with. Changing it requires restarting the device.
+    pub fn agent_uri(&self) -> &str {
+        &self.agent_uri
+    }
+
+    /// Targets this device can run, along with their configuration, ordered by ID.
+    pub fn targets(&self) -> Vec<(TargetId, TargetConfiguration)> {
+        let settings = self
+            .settings
+            .read()
+            .expect("Thread panicked with settings lock");
+        let mut targets: Vec<(TargetId, TargetConfiguration)> = settings
+            .targets
+            .iter()
+            .map(|(id, target)| (TargetId::new(id), target.clone()))
+            .collect();
+        targets.sort_by(|(a, _), (b, _)| a.as_string().cmp(b.as_string()));
+        targets
+    }
+
+    /// Resolves a target ID or alias to the ID of one of this device's targets.
+    pub fn resolve_target(&self, name: &str) -> Option<TargetId> {
+        self.settings
+            .read()
+            .expect("Thread panicked with settings lock")
+            .resolve_target(name)
+    }
+
+    /// Policy for turning the device off while it's running `target` and nobody is using it, along with the time zone
+    /// for its time window.
+    pub fn idle_policy(&self, target: &TargetId) -> Option<(IdlePolicy, Tz)> {
+        let settings = self
+            .settings
+            .read()
+            .expect("Thread panicked with settings lock");
+        settings
+            .idle_policy(target)
+            .map(|policy| (policy.clone(), settings.timezone))
+    }
+
+    /// Whether there are no actions in progress or queued.
+    pub fn is_idle(&self) -> bool {
+        self.queue().is_idle()
+    }
+
+    /// Waits for any in-progress and queued actions to finish.
+    pub async fn wait_idle(&self) {
+        let mut idle_rx = self.queue().idle_watch();
+        if *idle_rx.borrow() {
+            return;
+        }
+        while let Some(idle) = idle_rx.recv().await {
+            if idle {
+                return;
+            }
+        }
+    }
+
+    /// Stops the device accepting actions, then waits for those in progress or queued to finish, for the action
+    /// handler to exit, and for the history to be written. Another device with the same ID can then be started without
+    /// both writing its state.
+    pub async fn stop(&self) {
+        self.queue().stop();
+        // Wake the handler in case the queue is already empty, so that it notices
+        let _ = self.wake_tx.clone().try_send(());
+        self.wait_idle().await;
+        let mut handler_rx = self.handler_rx.clone();
+        while handler_rx.recv().await.is_some() {}
+        self.history.close().await;
+    }
+
+    /// Replaces the device's settings, first waiting for any in-progress action to finish. Returns whether the
+    /// settings changed.
+    pub async fn reconfigure(&self, new_settings: DeviceSettings) -> bool {
+        let _guard = self.action_lock.lock().await;
+        let mut settings = self
+            .settings
+            .write()
+            .expect("Thread panicked with settings lock");
+        if *settings == new_settings {
+            false
+        } else {
+            *settings = new_settings;
+            true
+        }
+    }
+
+    fn queue(&self) -> MutexGuard<'_, ActionQueue> {
+        self.queue.lock().expect("Thread panicked with queue mutex")
+    }
+
+    /// Queues an action according to the device's action policy, notifying `waiter` when it finishes. Returns the ID to
+    /// track the action by, or fails with `Busy` if the action was rejected.
+    fn enqueue(
+        &mut self,
+        action: Action,
+        source: Source,
+        waiter: Option<oneshot::Sender<ActionResult>>,
+        reserved: Option<ActionId>,
+    ) -> Result<ActionId> {
+        let action = self.resolve_action(action);
+        let policy = self
+            .settings
+            .read()
+            .expect("Thread panicked with settings lock")
+            .action_policy;
+        let action_name = action.to_string();
+        let id = {
+            let mut queue = self.queue();
+            if queue.is_stopped() {
+                bail!("Device {} has stopped", self.id);
+            }
+            queue.push(action, policy, waiter, reserved)?
+        };
+        self.history.record(EventKind::ActionReceived {
+            id,
+            action: action_name,
+            source: source.to_string(),
+        });
+
+        match self.wake_tx.try_send(()) {
+            // If a wakeup is already pending, the handler will see this action then
+            Ok(()) | Err(TrySendError::Full(())) => Ok(id),
+            Err(TrySendError::Closed(())) => bail!("Device {} has stopped", self.id),
+        }
+    }
+
+    /// Resolves aliases in `action`, so that requests for the same target coalesce.
+    fn resolve_action(&self, action: Action) -> Action {
+        match action {
+            Action::Run(target) => {
+                Action::Run(self.resolve_target(target.as_string()).unwrap_or(target))
+            }
+            action => action,
+        }
+    }
+
+    /// Cancels the action with the given ID, or the action in progress if there is no ID. Returns the ID of the
+    /// cancelled action.
+    pub fn cancel(&self, id: Option<ActionId>) -> Result<ActionId> {
+        match self.queue().cancel(id) {
+            Some(id) => Ok(id),
+            None => match id {
+                Some(id) => bail!("Action {} is not queued or in progress", id),
+                None => bail!("No action in progress"),
+            },
+        }
+    }
+
+    /// Tells the device to perform an action, without waiting for it to finish. Returns the ID to track it by.
+    pub async fn action(&mut self, action: Action, source: Source) -> Result<ActionId> {
+        self.enqueue(action, source, None, None)
+    }
+
+    /// Starts tracking an action the device will be told to perform later, returning the ID for `submit` to give it.
+    pub fn reserve(&self, action: Action) -> ActionId {
+        let action = self.resolve_action(action);
+        self.queue().reserve(&action)
+    }
+
+    /// Tells the device to perform an action, returning the ID to track it by along with a channel which receives the
+    /// result once it finishes. If an ID was reserved for the action, it's used to report the action's progress even
+    /// if the action couldn't be queued or was coalesced with another.
+    pub fn submit(
+        &mut self,
+        action: Action,
+        source: Source,
+        reserved: Option<ActionId>,
+    ) -> Result<(ActionId, oneshot::Receiver<ActionResult>)> {
+        let (done_tx, done_rx) = oneshot::channel();
+        let id = match self.enqueue(action, source, Some(done_tx), reserved) {
+            Ok(id) => id,
+            Err(error) => {
+                if let Some(reserved) = reserved {
+                    self.queue().release(reserved, Err(format!("{:#}", error)));
+                }
+                return Err(error);
+            }
+        };
+        match reserved {
+            Some(reserved) if reserved != id => {
+                // Report the result of the action this one was coalesced with under the reserved ID too
+                let (coalesced_tx, coalesced_rx) = oneshot::channel();
+                let queue = self.queue.clone();
+                tokio::spawn(async move {
+                    let result = done_rx.await.unwrap_or_else(|_| {
+                        Err("Device stopped before finishing the action".to_string())
+                    });
+                    queue
+                        .lock()
+                        .expect("Thread panicked with queue mutex")
+                        .release(reserved, result.clone());
+                    let _ = coalesced_tx.send(result);
+                });
+                Ok((reserved, coalesced_rx))
+            }
+            _ => Ok((id, done_rx)),
+        }
+    }
+
+    /// Performs an action on behalf of another device's action, waiting as long as this device is configured to take
+    /// for it. The action is cancelled if it takes longer, or if the future is dropped because the other device's
+    /// action was cancelled.
+    async fn perform_for(&mut self, action: Action, source: Source) -> Result<()> {
+        let timeout = self.action_timeout(&action);
+        let (done_tx, done_rx) = oneshot::channel();
+        let id = self.enqueue(action, source, Some(done_tx), None)?;
+        let mut guard = CancelOnDrop {
+            device: self.clone(),
+            id: Some(id),
+        };
+        let result = time::timeout(timeout, done_rx)
+            .await
+            .with_context(|| format!("Timed out after {}s", timeout.as_secs()))?;
+        guard.id = None;
+        result
+            .context("Device stopped before finishing the action")?
+            .map_err(|error| anyhow!(error))
+    }
+
+    /// How long the device is configured to take to perform `action`.
+    fn action_timeout(&self, action: &Action) -> Duration {
+        let settings = self
+            .settings
+            .read()
+            .expect("Thread panicked with settings lock");
+        let target = match (action, &*self.state_rx.borrow()) {
+            (Action::Run(target), _) | (_, State::Running(target)) => Some(target.clone()),
+            _ => settings.default_target.clone(),
+        };
+        let timing = settings.timing(target.as_ref());
+        match action {
+            Action::Suspend => timing.suspend_timeout,
+            Action::ShutDown => timing.shutdown_timeout,
+            _ => timing.boot_timeout,
+        }
     }
 
     /// The most recent observed state of this device.
@@ -379,6 +1727,7 @@ impl Device {
     }
 
     /// Poll for state updates.
+    #[allow(dead_code)]
     pub async fn recv_state(&mut self) -> Result<State> {
         if let Some(state) = self.state_rx.recv().await {
             Ok(state)
@@ -386,4 +1735,259 @@ impl Device {
             bail!("State channel closed");
         }
     }
+
+    /// The most recent details reported by this device's agent. Empty if the device is not running.
+    pub fn latest_report(&self) -> AgentReport {
+        self.report_rx.borrow().clone()
+    }
+
+    /// Report of the most recent maintenance run on this device, if there has been one.
+    pub fn latest_maintenance(&self) -> Option<MaintenanceReport> {
+        self.maintenance
+            .lock()
+            .expect("Thread panicked with maintenance mutex")
+            .clone()
+    }
+
+    /// Up to `limit` of the most recent history events at or after `since`, oldest first.
+    pub fn history(&self, since: Option<DateTime<Utc>>, limit: usize) -> Vec<Event> {
+        self.history.events_since(since, limit)
+    }
+
+    /// How long this device has taken to boot, shut down, and so on, measured at or after `since`.
+    pub fn stats(&self, since: Option<DateTime<Utc>>) -> Stats {
+        match since {
+            Some(_) => stats::compute(&self.history.events_since(since, usize::MAX)),
+            None => self.history.stats(),
+        }
+    }
+
+    /// What the controller has recorded about this device, including from before it restarted.
+    pub fn latest_record(&self) -> DeviceRecord {
+        self.store.get()
+    }
+
+    /// Keeps the device from suspending while idle until `ttl` has passed or the hold is released. Unlike actions,
+    /// this goes directly to the agent, since it doesn't change the state of the device.
+    pub async fn hold(&mut self, reason: String, ttl: Duration) -> Result<Hold> {
+        self.agent.hold(reason, ttl).await
+    }
+
+    /// Releases a hold taken with `hold`.
+    pub async fn release(&mut self, id: u64) -> Result<()> {
+        self.agent.release(id).await
+    }
+
+    /// Streams log entries from the device's agent.
+    pub async fn tail_logs(&mut self) -> Result<Streaming<LogEntry>> {
+        self.agent.tail_logs().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slog::Discard;
+
+    use super::*;
+    use crate::history::{Retention, MAX_EVENTS};
+
+    /// Creates a state tracker which doesn't persist anything, along with a receiver for the states it publishes.
+    fn state_tracker() -> (StateTracker, watch::Receiver<State>) {
+        let logger = Logger::root(Discard, o!());
+        let retention = Retention {
+            max_events: MAX_EVENTS,
+            max_age: None,
+        };
+        let store = StateStore::new(logger, None, retention).unwrap();
+        let id = DeviceId::new("test");
+        let (state_tx, state_rx) = watch::channel(State::Unknown);
+        let tracker = StateTracker {
+            observed: State::Unknown,
+            transition: None,
+            expect_off: false,
+            suspended: None,
+            state_tx,
+            store: store.open(&id),
+            history: store.history(&id),
+        };
+        (tracker, state_rx)
+    }
+
+    fn running(target: &str) -> State {
+        State::Running(TargetId::new(target))
+    }
+
+    #[test]
+    fn reboot_is_shown_until_transition_ends() {
+        let (mut tracker, state_rx) = state_tracker();
+        tracker.observe(&running("linux"));
+        let rebooting = State::Rebooting {
+            from: TargetId::new("linux"),
+            to: TargetId::new("linux"),
+        };
+        tracker.begin(rebooting.clone());
+
+        // Still up before the reboot, then down, then back up
+        for state in [running("linux"), State::Off, running("linux")].iter() {
+            tracker.observe(state);
+            assert_eq!(*state_rx.borrow(), rebooting);
+        }
+
+        tracker.end();
+        assert_eq!(*state_rx.borrow(), running("linux"));
+    }
+
+    #[test]
+    fn going_down_during_reboot_is_not_unreachable() {
+        let (mut tracker, _state_rx) = state_tracker();
+        tracker.observe(&running("linux"));
+        tracker.begin(State::Rebooting {
+            from: TargetId::new("linux"),
+            to: TargetId::new("windows"),
+        });
+        tracker.observe(&State::Off);
+        assert_eq!(tracker.observed, State::Off);
+    }
+
+    #[test]
+    fn unexpected_off_is_unreachable() {
+        let (mut tracker, state_rx) = state_tracker();
+        tracker.observe(&running("linux"));
+        tracker.observe(&State::Off);
+        assert_eq!(*state_rx.borrow(), State::Unreachable);
+
+        // Stays unreachable rather than off until it's seen running again
+        tracker.observe(&State::Off);
+        assert_eq!(*state_rx.borrow(), State::Unreachable);
+        tracker.observe(&running("linux"));
+        assert_eq!(*state_rx.borrow(), running("linux"));
+    }
+
+    #[test]
+    fn shutdown_is_off() {
+        let (mut tracker, state_rx) = state_tracker();
+        tracker.observe(&running("linux"));
+        tracker.begin(State::ShuttingDown);
+        assert_eq!(*state_rx.borrow(), State::ShuttingDown);
+        tracker.observe(&State::Off);
+        tracker.end();
+        assert_eq!(*state_rx.borrow(), State::Off);
+
+        // Still off, not unreachable, on later polls
+        tracker.observe(&State::Off);
+        assert_eq!(*state_rx.borrow(), State::Off);
+    }
+
+    #[test]
+    fn suspended_until_running_again() {
+        let (mut tracker, state_rx) = state_tracker();
+        tracker.observe(&running("linux"));
+        tracker.begin(State::Suspending);
+        tracker.observe(&State::Off);
+        tracker.suspend(TargetId::new("linux"));
+        tracker.end();
+        assert_eq!(*state_rx.borrow(), State::Suspended(TargetId::new("linux")));
+
+        tracker.observe(&State::Off);
+        assert_eq!(*state_rx.borrow(), State::Suspended(TargetId::new("linux")));
+
+        // Woken outside the controller, so it's no longer suspended once it goes off again
+        tracker.observe(&running("linux"));
+        assert_eq!(tracker.suspended, None);
+        tracker.observe(&State::Off);
+        assert_eq!(*state_rx.borrow(), State::Unreachable);
+    }
+
+    #[test]
+    fn off_at_startup_is_off() {
+        let (mut tracker, state_rx) = state_tracker();
+        tracker.observe(&State::Off);
+        assert_eq!(*state_rx.borrow(), State::Off);
+    }
+
+    /// Creates a handler for the device `id` in `config`, which sees it as `observed` and acts on `devices`.
+    fn handler(id: &str, config: &Configuration, devices: &Devices, observed: State) -> Handler {
+        let logger = Logger::root(Discard, o!());
+        let id = DeviceId::new(id);
+        let device_config = config.device_config(&id).unwrap();
+        let (tracker, _state_rx) = state_tracker();
+        let (_observed_tx, observed_rx) = watch::channel(observed);
+        let (_wake_tx, wake_rx) = mpsc::channel(1);
+        let store = tracker.store.clone();
+        let history = tracker.history.clone();
+        Handler {
+            id: id.clone(),
+            logger: logger.clone(),
+            agent: AgentConnection::new(device_config.agent().to_string(), &logger).unwrap(),
+            waker: Waker::new(),
+            settings: Arc::new(RwLock::new(DeviceSettings::new(config, device_config))),
+            action_lock: Arc::new(AsyncMutex::new(())),
+            maintenance: Arc::new(Mutex::new(None)),
+            state_tracker: Arc::new(Mutex::new(tracker)),
+            observed_rx,
+            queue: Arc::new(Mutex::new(ActionQueue::new(id, ActionTracker::new()))),
+            wake_rx,
+            grub_backup: None,
+            known_good: None,
+            store,
+            history,
+            devices: Arc::downgrade(devices),
+        }
+    }
+
+    #[tokio::test]
+    async fn dependents_are_refused_while_booting() {
+        let config: Configuration = toml::from_str(
+            "listen_address = \"127.0.0.1:3000\"\n\
+             tftp_directory = \"/srv/tftp\"\n\
+             default_interface = \"eth0\"\n\
+             [devices.nas]\n\
+             agent = \"http://127.0.0.1:1\"\n\
+             mac_address = \"11:22:33:44:55:66\"\n\
+             grub_config = \"nas.cfg\"\n\
+             dependents = \"refuse\"\n\
+             [devices.nas.targets.linux]\n\
+             menu_entry = \"Linux\"\n\
+             [devices.htpc]\n\
+             agent = \"http://127.0.0.1:1\"\n\
+             mac_address = \"11:22:33:44:55:77\"\n\
+             grub_config = \"htpc.cfg\"\n\
+             requires = [\"nas\"]\n\
+             [devices.htpc.targets.linux]\n\
+             menu_entry = \"Linux\"\n",
+        )
+        .unwrap();
+        let logger = Logger::root(Discard, o!());
+        let retention = Retention {
+            max_events: MAX_EVENTS,
+            max_age: None,
+        };
+        let devices: Devices = Arc::new(RwLock::new(HashMap::new()));
+        let services = Services {
+            waker: Waker::new(),
+            tracker: ActionTracker::new(),
+            store: StateStore::new(logger.clone(), None, retention).unwrap(),
+            devices: devices.clone(),
+        };
+        let mut htpc = Device::start(DeviceId::new("htpc"), &config, &services, &logger).unwrap();
+        htpc.state_rx = watch::channel(running("linux")).1;
+        devices
+            .write()
+            .unwrap()
+            .insert(htpc.id().clone(), htpc.clone());
+
+        // The NAS hasn't finished booting, but the HTPC already relies on it
+        let mut nas = handler(
+            "nas",
+            &config,
+            &devices,
+            State::Booting {
+                target: Some(TargetId::new("linux")),
+            },
+        );
+        let error = nas.stop_dependents(&Action::ShutDown).await.unwrap_err();
+        assert_eq!(error.to_string(), "Still required by htpc");
+
+        htpc.stop().await;
+    }
 }
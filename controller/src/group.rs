@@ -0,0 +1,96 @@
+//! Groups of devices which are acted on together
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use anyhow::Result;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use tokio::sync::oneshot;
+
+use crate::config::Configuration;
+use crate::device::{Action, Device, Source};
+use crate::id::{ActionId, DeviceId};
+use crate::queue::ActionResult;
+
+/// Members of each named group, which can change as the configuration is reloaded
+pub type Groups = Arc<RwLock<HashMap<String, Vec<DeviceId>>>>;
+
+/// Collects the groups defined in `config`.
+pub fn groups(config: &Configuration) -> HashMap<String, Vec<DeviceId>> {
+    config
+        .groups()
+        .map(|(name, members)| (name.to_string(), members))
+        .collect()
+}
+
+/// How to act on the devices in a group
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Strategy {
+    /// Act on every device at once
+    All,
+    /// Act on at most `max_in_flight` devices at a time, starting the next as each one finishes
+    Rolling { max_in_flight: usize },
+}
+
+/// Starts `action` on each of `devices` in the named group according to `strategy`, without waiting for it to finish.
+/// Returns the ID to follow each device's action by, or why it couldn't be started, in the same order as `devices`.
+/// Devices which have to wait their turn are given an ID straight away, and are told to act by a background task.
+pub fn start(
+    group: &str,
+    devices: Vec<Device>,
+    action: Action,
+    strategy: Strategy,
+) -> Vec<(DeviceId, Result<ActionId>)> {
+    let limit = match strategy {
+        Strategy::All => devices.len().max(1),
+        Strategy::Rolling { max_in_flight } => max_in_flight.max(1),
+    };
+    let source = Source::Group(group.to_string());
+
+    let mut results = Vec::new();
+    let in_flight = FuturesUnordered::new();
+    let mut waiting = Vec::new();
+    for mut device in devices {
+        let id = device.id().clone();
+        if in_flight.len() < limit {
+            let result =
+                device
+                    .submit(action.clone(), source.clone(), None)
+                    .map(|(id, done_rx)| {
+                        in_flight.push(done_rx);
+                        id
+                    });
+            results.push((id, result));
+        } else {
+            let reserved = device.reserve(action.clone());
+            waiting.push((device, reserved));
+            results.push((id, Ok(reserved)));
+        }
+    }
+
+    if !waiting.is_empty() {
+        tokio::spawn(roll(waiting, in_flight, limit, action, source));
+    }
+    results
+}
+
+/// Tells each of the `waiting` devices to perform `action` with the ID reserved for it, once fewer than `limit` actions
+/// are `in_flight`.
+async fn roll(
+    waiting: Vec<(Device, ActionId)>,
+    mut in_flight: FuturesUnordered<oneshot::Receiver<ActionResult>>,
+    limit: usize,
+    action: Action,
+    source: Source,
+) {
+    for (mut device, reserved) in waiting {
+        while in_flight.len() >= limit {
+            in_flight.next().await;
+        }
+        // Failures are reported under the reserved ID
+        if let Ok((_, done_rx)) = device.submit(action.clone(), source.clone(), Some(reserved)) {
+            in_flight.push(done_rx);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! Per-device log of state changes and actions, kept for a configured number of events or length of time
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use slog::{warn, Logger};
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, watch};
+
+use crate::device::State;
+use crate::id::{ActionId, TargetId};
+use crate::stats::{self, Phase, Stats};
+use crate::store;
+use crate::tracker::ActionStatus;
+
+/// Default number of events to keep for each device
+pub const MAX_EVENTS: usize = 1000;
+
+/// Number of measurements to keep for each phase and target, regardless of the retention limits
+pub const MAX_SAMPLES: usize = 100;
+
+/// Something that happened to a device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub time: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    /// The poller saw the device change state
+    StateChanged {
+        state: State,
+    },
+    /// An action was requested, possibly merging with one already queued
+    ActionReceived {
+        id: ActionId,
+        action: String,
+        source: String,
+    },
+    ActionStarted {
+        id: ActionId,
+        action: String,
+    },
+    /// The handler moved on to another step of an action
+    Step {
+        id: ActionId,
+        step: String,
+    },
+    ActionFinished {
+        id: ActionId,
+        action: String,
+        status: ActionStatus,
+        error: Option<String>,
+    },
+    /// How long a transition took, for duration statistics
+    Measured {
+        phase: Phase,
+        target: TargetId,
+        seconds: f64,
+    },
+}
+
+/// How long to keep events for
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Retention {
+    pub max_events: usize,
+    pub max_age: Option<Duration>,
+}
+
+/// Event log for a single device. Events are appended to a JSON lines file in the state directory as they happen, if
+/// there is one, which is compacted once it holds too many expired events.
+#[derive(Clone)]
+pub struct History {
+    retention: Retention,
+    events: Arc<Mutex<VecDeque<Event>>>,
+    /// Taken when the history is closed, so that the writer stops once it has written the events already recorded
+    write_tx: Arc<Mutex<Option<mpsc::UnboundedSender<Event>>>>,
+    /// Closed once the writer has stopped
+    written_rx: Option<watch::Receiver<()>>,
+    /// Statistics on the measurements kept, computed when first needed after one is recorded
+    stats: Arc<Mutex<Option<Stats>>>,
+}
+
+impl History {
+    /// Opens the history stored at `path`, or an in-memory history if there is none.
+    pub fn open(logger: &Logger, path: Option<PathBuf>, retention: Retention) -> History {
+        let mut history = History {
+            retention,
+            events: Arc::new(Mutex::new(VecDeque::new())),
+            write_tx: Arc::new(Mutex::new(None)),
+            written_rx: None,
+            stats: Arc::new(Mutex::new(None)),
+        };
+
+        if let Some(path) = path {
+            // Devices are started synchronously, and the history is bounded by the retention
+            match std::fs::read_to_string(&path) {
+                Ok(contents) => {
+                    let mut events = history.events();
+                    for line in contents.lines().filter(|line| !line.is_empty()) {
+                        match serde_json::from_str(line) {
+                            Ok(event) => events.push_back(event),
+                            Err(error) => {
+                                warn!(logger, "Skipping invalid history event: {}", error)
+                            }
+                        }
+                    }
+                }
+                Err(error) if error.kind() == ErrorKind::NotFound => (),
+                Err(error) => warn!(logger, "Could not read history: {}", error),
+            }
+            history.prune();
+
+            let (write_tx, write_rx) = mpsc::unbounded_channel();
+            let (written_tx, written_rx) = watch::channel(());
+            let kept = history.events().clone();
+            let logger = logger.clone();
+            tokio::spawn(async move {
+                write_events(logger, path, retention, kept, write_rx).await;
+                drop(written_tx);
+            });
+            *history.write_tx() = Some(write_tx);
+            history.written_rx = Some(written_rx);
+        }
+
+        history
+    }
+
+    fn events(&self) -> MutexGuard<'_, VecDeque<Event>> {
+        self.events
+            .lock()
+            .expect("Thread panicked with history mutex")
+    }
+
+    fn write_tx(&self) -> MutexGuard<'_, Option<mpsc::UnboundedSender<Event>>> {
+        self.write_tx
+            .lock()
+            .expect("Thread panicked with history writer mutex")
+    }
+
+    fn prune(&self) {
+        prune(&mut self.events(), self.retention);
+    }
+
+    fn cached_stats(&self) -> MutexGuard<'_, Option<Stats>> {
+        self.stats
+            .lock()
+            .expect("Thread panicked with history stats mutex")
+    }
+
+    /// Records that something happened.
+    pub fn record(&self, kind: EventKind) {
+        let measured = matches!(kind, EventKind::Measured { .. });
+        let event = Event {
+            time: Utc::now(),
+            kind,
+        };
+        self.events().push_back(event.clone());
+        self.prune();
+        if measured {
+            self.cached_stats().take();
+        }
+        if let Some(ref write_tx) = *self.write_tx() {
+            // If the writer stopped, the error was already logged
+            let _ = write_tx.send(event);
+        }
+    }
+
+    /// Stops writing to the history file, waiting for events already recorded to be written. Events recorded afterwards
+    /// are only kept in memory.
+    pub async fn close(&self) {
+        self.write_tx().take();
+        if let Some(mut written_rx) = self.written_rx.clone() {
+            while written_rx.recv().await.is_some() {}
+        }
+    }
+
+    /// Duration statistics for all the measurements kept.
+    pub fn stats(&self) -> Stats {
+        let mut cached = self.cached_stats();
+        if let Some(ref stats) = *cached {
+            return stats.clone();
+        }
+        let stats = stats::compute(&self.events().iter().cloned().collect::<Vec<Event>>());
+        *cached = Some(stats.clone());
+        stats
+    }
+
+    /// Returns up to `limit` of the most recent events at or after `since`, oldest first.
+    pub fn events_since(&self, since: Option<DateTime<Utc>>, limit: usize) -> Vec<Event> {
+        self.prune();
+        let events = self.events();
+        let mut matching: Vec<Event> = events
+            .iter()
+            .rev()
+            .take_while(|event| since.map_or(true, |since| event.time >= since))
+            .take(limit)
+            .cloned()
+            .collect();
+        matching.reverse();
+        matching
+    }
+}
+
+/// Drops events which are past the retention limits. Measurements don't count towards the limits, so that a device
+/// which changes state often keeps enough of them; only the latest `MAX_SAMPLES` are kept for each phase and target.
+fn prune(events: &mut VecDeque<Event>, retention: Retention) {
+    let cutoff = retention
+        .max_age
+        .and_then(|max_age| chrono::Duration::from_std(max_age).ok())
+        .map(|max_age| Utc::now() - max_age);
+    let mut kept_events = 0;
+    let mut kept_samples: BTreeMap<(Phase, &TargetId), usize> = BTreeMap::new();
+    let mut keep: Vec<bool> = events
+        .iter()
+        .rev()
+        .map(|event| match event.kind {
+            EventKind::Measured {
+                phase, ref target, ..
+            } => {
+                let samples = kept_samples.entry((phase, target)).or_default();
+                *samples += 1;
+                *samples <= MAX_SAMPLES
+            }
+            _ => {
+                kept_events += 1;
+                kept_events <= retention.max_events
+                    && cutoff.map_or(true, |cutoff| event.time >= cutoff)
+            }
+        })
+        .collect();
+    events.retain(|_| keep.pop().expect("One flag per event"));
+}
+
+/// Appends events to the history file in the order they were recorded, starting from the `kept` events already in
+/// it. Once the file holds twice as many events as are being kept, it's rewritten with just the kept events.
+async fn write_events(
+    logger: Logger,
+    path: PathBuf,
+    retention: Retention,
+    mut kept: VecDeque<Event>,
+    mut write_rx: mpsc::UnboundedReceiver<Event>,
+) {
+    // Start with a compacted file, since loading may have dropped expired events
+    let mut written = match compact(&path, &kept).await {
+        Ok(written) => written,
+        Err(error) => {
+            warn!(&logger, "Could not compact history: {:#}", error);
+            return;
+        }
+    };
+
+    while let Some(event) = write_rx.recv().await {
+        kept.push_back(event.clone());
+        prune(&mut kept, retention);
+        let result = if written >= 2 * kept.len().max(1) {
+            compact(&path, &kept).await
+        } else {
+            append(&path, &event).await.map(|()| written + 1)
+        };
+        match result {
+            Ok(count) => written = count,
+            Err(error) => warn!(&logger, "Could not write history: {:#}", error),
+        }
+    }
+}
+
+async fn append(path: &Path, event: &Event) -> Result<()> {
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .with_context(|| format!("Could not open {}", path.display()))?;
+    file.write_all(&line).await?;
+    Ok(())
+}
+
+/// Replaces the history file with the events currently kept, returning how many were written.
+async fn compact(path: &Path, events: &VecDeque<Event>) -> Result<usize> {
+    let mut contents = Vec::new();
+    for event in events.iter() {
+        contents.extend(serde_json::to_vec(event)?);
+        contents.push(b'\n');
+    }
+    store::write_atomically(path, contents).await?;
+    Ok(events.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(kind: EventKind) -> Event {
+        Event {
+            time: Utc::now(),
+            kind,
+        }
+    }
+
+    fn measured(target: &str) -> Event {
+        event(EventKind::Measured {
+            phase: Phase::Boot,
+            target: TargetId::new(target),
+            seconds: 1.0,
+        })
+    }
+
+    #[test]
+    fn measurements_are_kept_apart_from_other_events() {
+        let retention = Retention {
+            max_events: 2,
+            max_age: None,
+        };
+        let mut events: VecDeque<Event> = (0..MAX_SAMPLES + 1).map(|_| measured("linux")).collect();
+        events.push_back(measured("windows"));
+        for _ in 0..5 {
+            events.push_back(event(EventKind::StateChanged { state: State::Off }));
+        }
+
+        prune(&mut events, retention);
+
+        let count = |target: &str| {
+            events
+                .iter()
+                .filter(|event| match event.kind {
+                    EventKind::Measured { target: ref t, .. } => *t == TargetId::new(target),
+                    _ => false,
+                })
+                .count()
+        };
+        assert_eq!(count("linux"), MAX_SAMPLES);
+        assert_eq!(count("windows"), 1);
+        assert_eq!(events.len(), MAX_SAMPLES + 1 + 2);
+        assert!(matches!(
+            events.back().unwrap().kind,
+            EventKind::StateChanged { .. }
+        ));
+    }
+}
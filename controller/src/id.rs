@@ -2,11 +2,11 @@
 
 use std::fmt;
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 /// Identifier referring to a particular device. For example, `htpc` or `my-desktop`.
 #[repr(transparent)]
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct DeviceId(String);
 
 impl DeviceId {
@@ -38,7 +38,7 @@ impl slog::Value for DeviceId {
 
 /// Identifier for a bootable target. For example, `windows` or `ubuntu-lts`.
 #[repr(transparent)]
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct TargetId(String);
 
 impl TargetId {
@@ -51,9 +51,9 @@ impl TargetId {
     }
 }
 
-impl Into<String> for TargetId {
-    fn into(self) -> String {
-        self.0
+impl From<TargetId> for String {
+    fn from(id: TargetId) -> String {
+        id.0
     }
 }
 
@@ -62,3 +62,20 @@ impl fmt::Display for TargetId {
         f.write_str(self.as_string())
     }
 }
+
+/// Identifier for an action requested on a device, unique while the controller is running.
+#[repr(transparent)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub struct ActionId(u64);
+
+impl ActionId {
+    pub fn new(id: u64) -> ActionId {
+        ActionId(id)
+    }
+}
+
+impl fmt::Display for ActionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
@@ -0,0 +1,207 @@
+//! Turning devices off automatically when nobody is using them, according to their idle policies
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use slog::{info, warn, Logger};
+use tokio::time;
+
+use crate::agent::AgentReport;
+use crate::config::{IdleAction, IdlePolicy, SessionPolicy};
+use crate::device::{Action, Devices, Source, State};
+use crate::id::DeviceId;
+
+/// How often to check whether devices are idle
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long to wait before acting on a device again, if the last action didn't turn it off
+const RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Parses a time of day in an idle policy, such as `23:30`.
+pub fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M")
+        .with_context(|| format!("Invalid time of day `{}`, expected HH:MM", time))
+}
+
+/// Whether `now` falls within the policy's time window. A window which ends earlier in the day than it starts wraps
+/// past midnight.
+fn in_window(policy: &IdlePolicy, timezone: Tz, now: DateTime<Utc>) -> bool {
+    let now = now.with_timezone(&timezone).time();
+    let after = policy.after().and_then(|time| parse_time(time).ok());
+    let before = policy.before().and_then(|time| parse_time(time).ok());
+    match (after, before) {
+        (Some(after), Some(before)) if after <= before => after <= now && now < before,
+        (Some(after), Some(before)) => after <= now || now < before,
+        (Some(after), None) => after <= now,
+        (None, Some(before)) => now < before,
+        (None, None) => true,
+    }
+}
+
+/// Describes why `policy` applies to a device which reported `report`, or returns `None` if it doesn't.
+fn reason(
+    policy: &IdlePolicy,
+    timezone: Tz,
+    report: &AgentReport,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    // Holds are how users and other tools ask for the device to stay on
+    if policy.keep_awake() || !report.holds.is_empty() {
+        return None;
+    }
+    let activity = report.activity.as_ref()?;
+    if activity.idle < policy.idle()? || !in_window(policy, timezone, now) {
+        return None;
+    }
+
+    let mut reason = format!("idle for {}m", activity.idle.as_secs() / 60);
+    match policy.sessions() {
+        SessionPolicy::Any => (),
+        SessionPolicy::NoRemote => {
+            let sessions = activity.sessions.as_ref()?;
+            if sessions.iter().any(|session| session.host.is_some()) {
+                return None;
+            }
+            reason.push_str(" with no remote sessions");
+        }
+        SessionPolicy::None => {
+            if !activity.sessions.as_ref()?.is_empty() {
+                return None;
+            }
+            reason.push_str(" with no sessions");
+        }
+    }
+    match (policy.after(), policy.before()) {
+        (Some(after), Some(before)) => {
+            reason.push_str(&format!(" between {} and {}", after, before))
+        }
+        (Some(after), None) => reason.push_str(&format!(" after {}", after)),
+        (None, Some(before)) => reason.push_str(&format!(" before {}", before)),
+        (None, None) => (),
+    }
+    Some(reason)
+}
+
+/// Periodically checks every running device against its idle policy, suspending or shutting it down through its action
+/// queue when the policy applies. Devices which are busy with another action are left alone.
+pub async fn monitor(logger: Logger, devices: Devices) {
+    let mut triggered: HashMap<DeviceId, Instant> = HashMap::new();
+    let mut tick = time::interval(CHECK_INTERVAL);
+    loop {
+        tick.tick().await;
+        let devices: Vec<_> = devices
+            .read()
+            .expect("Thread panicked with devices lock")
+            .values()
+            .cloned()
+            .collect();
+        triggered.retain(|_, at| at.elapsed() < RETRY_INTERVAL);
+
+        for mut device in devices {
+            let target = match device.latest_state() {
+                State::Running(target) => target,
+                _ => continue,
+            };
+            if triggered.contains_key(device.id()) || !device.is_idle() {
+                continue;
+            }
+            let (policy, timezone) = match device.idle_policy(&target) {
+                Some(policy) => policy,
+                None => continue,
+            };
+            let reason = match reason(&policy, timezone, &device.latest_report(), Utc::now()) {
+                Some(reason) => reason,
+                None => continue,
+            };
+
+            let action = match policy.action() {
+                IdleAction::Suspend => Action::Suspend,
+                IdleAction::Shutdown => Action::ShutDown,
+            };
+            info!(&logger, "Device is idle"; "device" => device.id(), "action" => %action, "reason" => &reason);
+            triggered.insert(device.id().clone(), Instant::now());
+            if let Err(error) = device.action(action, Source::Idle(reason)).await {
+                warn!(&logger, "Could not act on idle device: {:#}", error; "device" => device.id());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::agent::{Activity, Session};
+
+    fn policy(source: &str) -> IdlePolicy {
+        toml::from_str(source).unwrap()
+    }
+
+    /// A report from a device idle for `minutes`, with sessions from the given hosts (`None` for local sessions).
+    fn report(minutes: u64, hosts: &[Option<&str>]) -> AgentReport {
+        let sessions = hosts
+            .iter()
+            .map(|host| Session {
+                user: "user".to_string(),
+                host: host.map(str::to_string),
+            })
+            .collect();
+        AgentReport {
+            activity: Some(Activity {
+                idle: Duration::from_secs(minutes * 60),
+                sessions: Some(sessions),
+            }),
+            ..AgentReport::default()
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.ymd(2020, 6, 1).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn idle_device_without_remote_sessions() {
+        let policy = policy("idle = 1800");
+        assert_eq!(
+            reason(&policy, Tz::UTC, &report(45, &[None]), at(12, 0)).as_deref(),
+            Some("idle for 45m with no remote sessions")
+        );
+        assert_eq!(reason(&policy, Tz::UTC, &report(20, &[]), at(12, 0)), None);
+        assert_eq!(
+            reason(&policy, Tz::UTC, &report(45, &[Some("laptop")]), at(12, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn keep_awake() {
+        let policy = policy("keep_awake = true");
+        assert_eq!(reason(&policy, Tz::UTC, &report(600, &[]), at(12, 0)), None);
+    }
+
+    #[test]
+    fn window_wraps_past_midnight() {
+        let policy = policy("idle = 60\nafter = \"23:00\"\nbefore = \"06:00\"\nsessions = \"any\"");
+        let report = report(5, &[Some("laptop")]);
+        assert_eq!(
+            reason(&policy, Tz::UTC, &report, at(1, 30)).as_deref(),
+            Some("idle for 5m between 23:00 and 06:00")
+        );
+        assert_eq!(reason(&policy, Tz::UTC, &report, at(12, 0)), None);
+        // 23:30 in Berlin during summer time
+        assert!(reason(&policy, chrono_tz::Europe::Berlin, &report, at(21, 30)).is_some());
+    }
+
+    #[test]
+    fn unknown_sessions_are_not_idle() {
+        let policy = policy("idle = 60\nsessions = \"none\"");
+        let mut report = report(5, &[]);
+        assert!(reason(&policy, Tz::UTC, &report, at(12, 0)).is_some());
+        report.activity.as_mut().unwrap().sessions = None;
+        assert_eq!(reason(&policy, Tz::UTC, &report, at(12, 0)), None);
+    }
+}
@@ -1,20 +1,40 @@
 #![feature(async_closure)]
+#![recursion_limit = "256"]
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 
-use anyhow::Result;
-use slog::{debug, o, Drain, Logger};
+use anyhow::{bail, Result};
+use slog::{debug, error, info, o, Drain, Logger};
 use structopt::StructOpt;
+use tokio::signal::unix::{signal, SignalKind};
 
 use crate::config::Configuration;
-use crate::device::Device;
+use crate::device::{Device, Devices, Services};
+use crate::group::Groups;
+use crate::history::Retention;
 use crate::id::DeviceId;
+use crate::reload::Reloader;
+use crate::schedule::Scheduler;
+use crate::store::StateStore;
+use crate::tracker::ActionTracker;
 use crate::wake::Waker;
 
 mod agent;
+mod check;
 mod device;
+mod group;
+mod history;
+mod idle;
+mod maintenance;
+mod queue;
+mod reload;
+mod rpc;
+mod schedule;
 mod server;
+mod stats;
+mod store;
+mod tracker;
 mod wake;
 
 mod config;
@@ -30,6 +50,16 @@ pub struct Args {
     #[structopt(long = "--config")]
     #[structopt(parse(from_os_str))]
     pub config_path: PathBuf,
+
+    #[structopt(subcommand)]
+    pub command: Option<Subcommand>,
+}
+
+/// Alternatives to running the controller. If no command is given, runs the controller itself.
+#[derive(StructOpt)]
+pub enum Subcommand {
+    /// Check the configuration for problems, reporting all of them
+    CheckConfig,
 }
 
 fn create_logger() -> Logger {
@@ -43,11 +73,14 @@ fn create_logger() -> Logger {
 }
 
 /// Starts a background task for each configured device, returning a map of device handles
-fn start_devices(logger: &Logger, config: &Configuration) -> Result<HashMap<DeviceId, Device>> {
-    let waker = Waker::new();
+fn start_devices(
+    logger: &Logger,
+    config: &Configuration,
+    services: &Services,
+) -> Result<HashMap<DeviceId, Device>> {
     let mut devices = HashMap::new();
     for id in config.devices() {
-        let device = Device::start(id.clone(), &config, waker.clone(), logger)?;
+        let device = Device::start(id.clone(), config, services, logger)?;
 
         devices.insert(id, device);
     }
@@ -62,8 +95,105 @@ async fn main() -> Result<()> {
     debug!(&logger, "Loading configuration"; "path" => args.config_path.display());
     let config = Configuration::load_file(&args.config_path).await?;
 
-    let devices = Arc::new(start_devices(&logger, &config)?);
+    let problems = check::check(&config).await;
+    if let Some(Subcommand::CheckConfig) = args.command {
+        for problem in problems.iter() {
+            println!("{}", problem);
+        }
+        if problems.is_empty() {
+            println!("Configuration OK");
+            return Ok(());
+        } else {
+            bail!("Found {} configuration problems", problems.len());
+        }
+    }
+
+    if !problems.is_empty() {
+        for problem in problems.iter() {
+            error!(&logger, "Configuration problem: {}", problem);
+        }
+        bail!("Found {} configuration problems", problems.len());
+    }
 
-    server::serve(logger.clone(), devices, config.listen_address()).await;
+    let tracker = ActionTracker::new();
+    // Devices are added once started, since they need the other services to start
+    let devices: Devices = Arc::new(RwLock::new(HashMap::new()));
+    let services = Services {
+        waker: Waker::new(),
+        tracker: tracker.clone(),
+        store: StateStore::new(
+            logger.clone(),
+            config.state_directory().map(PathBuf::from),
+            Retention {
+                max_events: config.history_max_events().unwrap_or(history::MAX_EVENTS),
+                max_age: config.history_max_age(),
+            },
+        )?,
+        devices: devices.clone(),
+    };
+    *devices.write().expect("Thread panicked with devices lock") =
+        start_devices(&logger, &config, &services)?;
+    let groups: Groups = Arc::new(RwLock::new(group::groups(&config)));
+    let scheduler = Scheduler::start(logger.clone(), &config, &services.store, devices.clone());
+    let reloader = Arc::new(Reloader::new(
+        logger.clone(),
+        args.config_path.clone(),
+        config.clone(),
+        services,
+        devices.clone(),
+        groups.clone(),
+        scheduler.clone(),
+    ));
+    tokio::spawn(reload_on_hangup(logger.clone(), reloader.clone()));
+    tokio::spawn(idle::monitor(logger.clone(), devices.clone()));
+
+    match config.rpc_listen_address() {
+        Some(rpc_addr) => {
+            let http = async {
+                server::serve(
+                    logger.clone(),
+                    devices.clone(),
+                    groups,
+                    scheduler,
+                    tracker,
+                    reloader,
+                    config.listen_address(),
+                )
+                .await;
+                Ok(())
+            };
+            tokio::try_join!(http, rpc::serve(logger.clone(), devices.clone(), rpc_addr))?;
+        }
+        None => {
+            server::serve(
+                logger.clone(),
+                devices,
+                groups,
+                scheduler,
+                tracker,
+                reloader,
+                config.listen_address(),
+            )
+            .await
+        }
+    }
     Ok(())
 }
+
+/// Reloads the configuration whenever the controller receives SIGHUP
+async fn reload_on_hangup(logger: Logger, reloader: Arc<Reloader>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!(&logger, "Could not listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+
+    while hangups.recv().await.is_some() {
+        match reloader.reload().await {
+            Ok(summary) => info!(&logger, "Reloaded configuration: {:?}", summary),
+            Err(e) => error!(&logger, "Reloading configuration failed: {:#}", e),
+        }
+    }
+}
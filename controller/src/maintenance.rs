@@ -0,0 +1,84 @@
+//! Records of maintenance runs, where a device is booted into its update target, updated, and restored.
+
+use std::sync::{Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// What happened during a maintenance run
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceReport {
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    pub steps: Vec<MaintenanceStep>,
+    /// Why the run failed, if it did
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceStep {
+    pub time: DateTime<Utc>,
+    pub description: String,
+}
+
+/// Shared record of the most recent maintenance run on a device
+pub type LatestMaintenance = Arc<Mutex<Option<MaintenanceReport>>>;
+
+/// Handle for recording steps of an in-progress maintenance run
+pub struct MaintenanceLog {
+    latest: LatestMaintenance,
+}
+
+impl MaintenanceLog {
+    /// Starts recording a new maintenance run, replacing the previous report.
+    pub fn start(latest: LatestMaintenance) -> MaintenanceLog {
+        *latest
+            .lock()
+            .expect("Thread panicked with maintenance mutex") = Some(MaintenanceReport {
+            started: Utc::now(),
+            finished: None,
+            steps: Vec::new(),
+            error: None,
+        });
+        MaintenanceLog { latest }
+    }
+
+    fn update<F: FnOnce(&mut MaintenanceReport)>(&self, f: F) {
+        let mut latest = self
+            .latest
+            .lock()
+            .expect("Thread panicked with maintenance mutex");
+        if let Some(ref mut report) = *latest {
+            f(report);
+        }
+    }
+
+    /// Records that a step was taken.
+    pub fn step<S: Into<String>>(&self, description: S) {
+        let step = MaintenanceStep {
+            time: Utc::now(),
+            description: description.into(),
+        };
+        self.update(|report| report.steps.push(step));
+    }
+
+    /// Records that the run finished, failing if `error` is set. If the log is dropped without finishing, the run is
+    /// recorded as cancelled.
+    pub fn finish(&self, error: Option<String>) {
+        self.update(|report| {
+            report.finished = Some(Utc::now());
+            report.error = error;
+        });
+    }
+}
+
+impl Drop for MaintenanceLog {
+    fn drop(&mut self) {
+        self.update(|report| {
+            if report.finished.is_none() {
+                report.finished = Some(Utc::now());
+                report.error = Some("Cancelled".to_string());
+            }
+        });
+    }
+}
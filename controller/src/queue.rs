@@ -0,0 +1,461 @@
+//! Per-device queue of actions waiting for the handler
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+
+use anyhow::Result;
+use tokio::sync::{oneshot, watch};
+
+use crate::config::ActionPolicy;
+use crate::device::Action;
+use crate::id::{ActionId, DeviceId};
+use crate::tracker::{ActionStatus, ActionTracker};
+
+/// Outcome of an action. Errors are formatted, since the same result can go to several waiters.
+pub type ActionResult = std::result::Result<(), String>;
+
+/// Error when an action is rejected because the device is busy with another one
+#[derive(Debug)]
+pub struct Busy {
+    pub in_progress: Action,
+}
+
+impl fmt::Display for Busy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Device is busy with `{}`", self.in_progress)
+    }
+}
+
+impl Error for Busy {}
+
+/// Error for an action that was cancelled before it finished
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+/// Status and shareable result for how an action ended.
+pub fn outcome(result: &Result<()>) -> (ActionStatus, ActionResult) {
+    match result {
+        Ok(()) => (ActionStatus::Succeeded, Ok(())),
+        Err(error) if error.is::<Cancelled>() => (ActionStatus::Cancelled, Err(error.to_string())),
+        Err(error) => (ActionStatus::Failed, Err(format!("{:#}", error))),
+    }
+}
+
+/// An action, along with everyone waiting for it to finish
+struct QueuedAction {
+    id: ActionId,
+    action: Action,
+    waiters: Vec<oneshot::Sender<ActionResult>>,
+}
+
+impl QueuedAction {
+    /// Reports how the action ended to the tracker and everyone waiting for it.
+    fn finish(self, tracker: &ActionTracker, status: ActionStatus, result: ActionResult) {
+        tracker.finished(self.id, status, result.clone().err());
+        for waiter in self.waiters {
+            // The waiter may have given up
+            let _ = waiter.send(result.clone());
+        }
+    }
+}
+
+/// The action the handler is working on, and those waiting to be handled after it. Changes are reported to the
+/// action tracker.
+pub struct ActionQueue {
+    device: DeviceId,
+    tracker: ActionTracker,
+    current: Option<QueuedAction>,
+    /// Stops the handler working on the current action
+    cancel_current: Option<oneshot::Sender<()>>,
+    pending: VecDeque<QueuedAction>,
+    /// Other devices the action in progress is waiting for
+    waiting_on: Vec<DeviceId>,
+    /// Set once the device is being stopped, after which no more actions are accepted
+    stopped: bool,
+    /// Whether the queue is idle, for waiting until it is
+    idle_tx: watch::Sender<bool>,
+    idle_rx: watch::Receiver<bool>,
+}
+
+impl ActionQueue {
+    pub fn new(device: DeviceId, tracker: ActionTracker) -> ActionQueue {
+        let (idle_tx, idle_rx) = watch::channel(true);
+        ActionQueue {
+            device,
+            tracker,
+            current: None,
+            cancel_current: None,
+            pending: VecDeque::new(),
+            waiting_on: Vec::new(),
+            stopped: false,
+            idle_tx,
+            idle_rx,
+        }
+    }
+
+    /// Adds `action` to the queue according to `policy`, returning its ID. If the same action is already in progress
+    /// or pending, the two are coalesced, so the existing action's ID is returned and `waiter` is notified when it
+    /// finishes. Otherwise the action is given the `reserved` ID if there is one.
+    pub fn push(
+        &mut self,
+        action: Action,
+        policy: ActionPolicy,
+        waiter: Option<oneshot::Sender<ActionResult>>,
+        reserved: Option<ActionId>,
+    ) -> Result<ActionId, Busy> {
+        if policy == ActionPolicy::Replace {
+            let replaced = format!("Replaced by `{}`", action);
+            for queued in std::mem::take(&mut self.pending) {
+                if queued.action == action {
+                    self.pending.push_back(queued);
+                } else {
+                    queued.finish(
+                        &self.tracker,
+                        ActionStatus::Cancelled,
+                        Err(replaced.clone()),
+                    );
+                }
+            }
+        }
+
+        let existing = self
+            .current
+            .iter_mut()
+            .chain(self.pending.iter_mut())
+            .find(|queued| queued.action == action);
+        if let Some(existing) = existing {
+            existing.waiters.extend(waiter);
+            return Ok(existing.id);
+        }
+
+        if policy == ActionPolicy::Reject {
+            if let Some(busy) = self.current.as_ref().or_else(|| self.pending.front()) {
+                return Err(Busy {
+                    in_progress: busy.action.clone(),
+                });
+            }
+        }
+
+        let id = reserved.unwrap_or_else(|| self.tracker.queued(&self.device, &action));
+        self.pending.push_back(QueuedAction {
+            id,
+            action,
+            waiters: waiter.into_iter().collect(),
+        });
+        self.idle_changed();
+        Ok(id)
+    }
+
+    /// Starts tracking an action which will be pushed later, returning the ID to push it with.
+    pub fn reserve(&self, action: &Action) -> ActionId {
+        self.tracker.queued(&self.device, action)
+    }
+
+    /// Finishes tracking a reserved action which didn't take its ID, because it was coalesced or rejected.
+    pub fn release(&self, id: ActionId, result: ActionResult) {
+        let status = match result {
+            Ok(()) => ActionStatus::Succeeded,
+            Err(_) => ActionStatus::Failed,
+        };
+        self.tracker.finished(id, status, result.err());
+    }
+
+    /// Starts the next pending action, returning it along with a channel that fires if it's cancelled. Must only be
+    /// called when no action is in progress.
+    pub fn start(&mut self) -> Option<(ActionId, Action, oneshot::Receiver<()>)> {
+        debug_assert!(self.current.is_none());
+        self.current = self.pending.pop_front();
+        let current = self.current.as_ref()?;
+        self.tracker.started(current.id);
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        self.cancel_current = Some(cancel_tx);
+        Some((current.id, current.action.clone(), cancel_rx))
+    }
+
+    /// Cancels the action with the given ID, or the action in progress if there is no ID. Pending actions are removed
+    /// from the queue, while the handler is told to stop working on the current action. Returns the ID of the
+    /// cancelled action, or `None` if there was no such action to cancel.
+    pub fn cancel(&mut self, id: Option<ActionId>) -> Option<ActionId> {
+        if let Some(ref current) = self.current {
+            if id.is_none() || id == Some(current.id) {
+                if let Some(cancel) = self.cancel_current.take() {
+                    // If the handler already finished the action, there's nothing to cancel
+                    let _ = cancel.send(());
+                }
+                return Some(current.id);
+            }
+        }
+
+        let index = self
+            .pending
+            .iter()
+            .position(|queued| Some(queued.id) == id)?;
+        let queued = self.pending.remove(index)?;
+        let id = queued.id;
+        queued.finish(
+            &self.tracker,
+            ActionStatus::Cancelled,
+            Err(Cancelled.to_string()),
+        );
+        self.idle_changed();
+        Some(id)
+    }
+
+    /// Records the step the action in progress is on.
+    pub fn step<S: Into<String>>(&self, step: S) {
+        if let Some(ref current) = self.current {
+            self.tracker.step(current.id, step);
+        }
+    }
+
+    /// Records how many Wake-on-LAN packets the action in progress has sent.
+    pub fn wake_packets(&self, packets: u32) {
+        if let Some(ref current) = self.current {
+            self.tracker.wake_packets(current.id, packets);
+        }
+    }
+
+    /// ID of the action in progress, if there is one.
+    pub fn current(&self) -> Option<ActionId> {
+        self.current.as_ref().map(|current| current.id)
+    }
+
+    /// Finishes the action in progress, notifying anyone waiting for it.
+    pub fn finish(&mut self, result: &Result<()>) {
+        let (status, result) = outcome(result);
+        self.cancel_current = None;
+        self.waiting_on.clear();
+        if let Some(current) = self.current.take() {
+            current.finish(&self.tracker, status, result);
+        }
+        self.idle_changed();
+    }
+
+    /// Records which other devices the action in progress is waiting for, replacing any recorded before.
+    pub fn wait_on(&mut self, devices: Vec<DeviceId>) {
+        self.waiting_on = devices;
+    }
+
+    /// Whether the action in progress is waiting for `device`.
+    pub fn is_waiting_on(&self, device: &DeviceId) -> bool {
+        self.waiting_on.contains(device)
+    }
+
+    /// Whether there are no actions in progress or pending.
+    pub fn is_idle(&self) -> bool {
+        self.current.is_none() && self.pending.is_empty()
+    }
+
+    /// Receives whether the queue is idle each time that changes.
+    pub fn idle_watch(&self) -> watch::Receiver<bool> {
+        self.idle_rx.clone()
+    }
+
+    fn idle_changed(&self) {
+        let idle = self.is_idle();
+        if *self.idle_rx.borrow() != idle {
+            // The queue holds a receiver, so the channel can't be closed
+            let _ = self.idle_tx.broadcast(idle);
+        }
+    }
+
+    /// Stops accepting actions. Those in progress or pending are still handled.
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+
+    /// Whether the queue has stopped accepting actions.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::id::TargetId;
+
+    fn queue() -> (ActionQueue, ActionTracker) {
+        let tracker = ActionTracker::new();
+        (
+            ActionQueue::new(DeviceId::new("test"), tracker.clone()),
+            tracker,
+        )
+    }
+
+    fn run(target: &str) -> Action {
+        Action::Run(TargetId::new(target))
+    }
+
+    fn status(tracker: &ActionTracker, id: ActionId) -> (ActionStatus, Option<String>) {
+        let record = tracker.get(id).unwrap();
+        (record.status, record.error)
+    }
+
+    #[test]
+    fn queue_policy_runs_in_order() {
+        let (mut queue, _) = queue();
+        let first = queue
+            .push(Action::Boot, ActionPolicy::Queue, None, None)
+            .unwrap();
+        let second = queue
+            .push(run("windows"), ActionPolicy::Queue, None, None)
+            .unwrap();
+        assert_ne!(first, second);
+
+        let (id, action, _) = queue.start().unwrap();
+        assert_eq!((id, action), (first, Action::Boot));
+        queue.finish(&Ok(()));
+        let (id, action, _) = queue.start().unwrap();
+        assert_eq!((id, action), (second, run("windows")));
+        queue.finish(&Ok(()));
+        assert!(queue.start().is_none());
+    }
+
+    #[test]
+    fn identical_actions_are_coalesced() {
+        let (mut queue, tracker) = queue();
+        let (first_tx, mut first_rx) = oneshot::channel();
+        let (second_tx, mut second_rx) = oneshot::channel();
+        let id = queue
+            .push(run("linux"), ActionPolicy::Queue, Some(first_tx), None)
+            .unwrap();
+        queue.start().unwrap();
+
+        // Coalesced with the action in progress, even under the reject policy
+        let again = queue
+            .push(run("linux"), ActionPolicy::Reject, Some(second_tx), None)
+            .unwrap();
+        assert_eq!(again, id);
+
+        queue.finish(&Err(anyhow::anyhow!("No agent")));
+        assert_eq!(first_rx.try_recv().unwrap(), Err("No agent".to_string()));
+        assert_eq!(second_rx.try_recv().unwrap(), Err("No agent".to_string()));
+        assert_eq!(status(&tracker, id).0, ActionStatus::Failed);
+        assert!(queue.start().is_none());
+    }
+
+    #[test]
+    fn replace_policy_cancels_pending_actions() {
+        let (mut queue, tracker) = queue();
+        let current = queue
+            .push(Action::Boot, ActionPolicy::Queue, None, None)
+            .unwrap();
+        queue.start().unwrap();
+        let (waiter_tx, mut waiter_rx) = oneshot::channel();
+        let replaced = queue
+            .push(run("windows"), ActionPolicy::Queue, Some(waiter_tx), None)
+            .unwrap();
+        let kept = queue
+            .push(Action::ShutDown, ActionPolicy::Queue, None, None)
+            .unwrap();
+
+        let id = queue
+            .push(Action::ShutDown, ActionPolicy::Replace, None, None)
+            .unwrap();
+        assert_eq!(id, kept);
+        assert_eq!(
+            status(&tracker, replaced),
+            (
+                ActionStatus::Cancelled,
+                Some("Replaced by `shut down`".to_string())
+            )
+        );
+        assert_eq!(
+            waiter_rx.try_recv().unwrap(),
+            Err("Replaced by `shut down`".to_string())
+        );
+
+        // The action in progress isn't affected
+        assert_eq!(queue.current(), Some(current));
+        queue.finish(&Ok(()));
+        assert_eq!(queue.start().unwrap().0, kept);
+    }
+
+    #[test]
+    fn reject_policy_fails_when_busy() {
+        let (mut queue, _) = queue();
+        queue
+            .push(Action::Boot, ActionPolicy::Reject, None, None)
+            .unwrap();
+        let busy = queue
+            .push(Action::Reboot, ActionPolicy::Reject, None, None)
+            .unwrap_err();
+        assert_eq!(busy.in_progress, Action::Boot);
+
+        queue.start().unwrap();
+        queue.finish(&Ok(()));
+        assert!(queue
+            .push(Action::Reboot, ActionPolicy::Reject, None, None)
+            .is_ok());
+    }
+
+    #[test]
+    fn cancel() {
+        let (mut queue, tracker) = queue();
+        let current = queue
+            .push(Action::Boot, ActionPolicy::Queue, None, None)
+            .unwrap();
+        let pending = queue
+            .push(Action::Reboot, ActionPolicy::Queue, None, None)
+            .unwrap();
+        let (_, _, mut cancel_rx) = queue.start().unwrap();
+
+        assert_eq!(queue.cancel(Some(pending)), Some(pending));
+        assert_eq!(status(&tracker, pending).0, ActionStatus::Cancelled);
+        assert_eq!(queue.cancel(Some(pending)), None);
+
+        // The handler is told to stop the current action, and finishes it
+        assert_eq!(queue.cancel(None), Some(current));
+        assert!(cancel_rx.try_recv().is_ok());
+        queue.finish(&Err(Cancelled.into()));
+        assert_eq!(status(&tracker, current).0, ActionStatus::Cancelled);
+        assert!(queue.is_idle());
+    }
+
+    #[test]
+    fn reserved_id_is_used_unless_coalesced() {
+        let (mut queue, tracker) = queue();
+        let reserved = queue.reserve(&Action::Boot);
+        assert_eq!(status(&tracker, reserved).0, ActionStatus::Queued);
+        let id = queue
+            .push(Action::Boot, ActionPolicy::Queue, None, Some(reserved))
+            .unwrap();
+        assert_eq!(id, reserved);
+
+        let other = queue.reserve(&Action::Boot);
+        let id = queue
+            .push(Action::Boot, ActionPolicy::Queue, None, Some(other))
+            .unwrap();
+        assert_eq!(id, reserved);
+        queue.release(other, Err("Failed".to_string()));
+        assert_eq!(
+            status(&tracker, other),
+            (ActionStatus::Failed, Some("Failed".to_string()))
+        );
+    }
+
+    #[test]
+    fn idle_watch() {
+        let (mut queue, _) = queue();
+        let idle_rx = queue.idle_watch();
+        assert!(*idle_rx.borrow());
+        queue
+            .push(Action::Boot, ActionPolicy::Queue, None, None)
+            .unwrap();
+        assert!(!*idle_rx.borrow());
+        queue.start().unwrap();
+        assert!(!*idle_rx.borrow());
+        queue.finish(&Ok(()));
+        assert!(*idle_rx.borrow());
+    }
+}
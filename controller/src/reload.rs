@@ -0,0 +1,186 @@
+//! Reloading the configuration without restarting the controller
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{RwLockReadGuard, RwLockWriteGuard};
+
+use anyhow::{bail, Result};
+use futures::future;
+use serde::Serialize;
+use slog::{info, warn, Logger};
+use tokio::sync::Mutex;
+
+use crate::check;
+use crate::config::Configuration;
+use crate::device::{Device, DeviceSettings, Devices, Services};
+use crate::group::{self, Groups};
+use crate::id::DeviceId;
+use crate::schedule::Scheduler;
+
+/// Applies changes to the configuration file to running devices.
+pub struct Reloader {
+    logger: Logger,
+    config_path: PathBuf,
+    services: Services,
+    devices: Devices,
+    groups: Groups,
+    scheduler: Scheduler,
+    /// The running configuration. Locked for the duration of a reload so that reloads don't overlap.
+    current: Mutex<Configuration>,
+}
+
+/// Devices affected by a reload
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub updated: Vec<String>,
+    /// Devices which were stopped and started again, because a setting that can't be changed in place changed
+    pub restarted: Vec<String>,
+    /// Devices which couldn't be started, with the reason. They're left out until the next reload.
+    pub failed: Vec<String>,
+}
+
+impl Reloader {
+    pub fn new(
+        logger: Logger,
+        config_path: PathBuf,
+        config: Configuration,
+        services: Services,
+        devices: Devices,
+        groups: Groups,
+        scheduler: Scheduler,
+    ) -> Reloader {
+        Reloader {
+            logger,
+            config_path,
+            services,
+            devices,
+            groups,
+            scheduler,
+            current: Mutex::new(config),
+        }
+    }
+
+    /// Loads the configuration file again and applies any changes. If the new configuration is invalid, nothing is
+    /// changed. Devices which fail to start are reported in the summary, and the rest of the configuration is still
+    /// applied.
+    pub async fn reload(&self) -> Result<ReloadSummary> {
+        let mut current = self.current.lock().await;
+
+        info!(&self.logger, "Reloading configuration"; "path" => self.config_path.display());
+        let config = Configuration::load_file(&self.config_path).await?;
+        let problems = check::check(&config).await;
+        if !problems.is_empty() {
+            bail!(
+                "Found {} configuration problems: {}",
+                problems.len(),
+                problems.join("; ")
+            );
+        }
+
+        if config.listen_address() != current.listen_address()
+            || config.rpc_listen_address() != current.rpc_listen_address()
+        {
+            warn!(
+                &self.logger,
+                "Listen addresses changed, but a restart is needed to apply them"
+            );
+        }
+
+        if config.state_directory() != current.state_directory() {
+            warn!(
+                &self.logger,
+                "State directory changed, but a restart is needed to apply it"
+            );
+        }
+
+        if config.history_max_events() != current.history_max_events()
+            || config.history_max_age() != current.history_max_age()
+        {
+            warn!(
+                &self.logger,
+                "History retention changed, but a restart is needed to apply it"
+            );
+        }
+
+        let mut summary = ReloadSummary::default();
+
+        let removed: Vec<Device> = current
+            .devices()
+            .filter(|id| config.device_config(id).is_none())
+            .filter_map(|id| self.devices_mut().remove(&id))
+            .collect();
+        // Wait for in-progress actions, so that the old tasks are done writing the device's state if it's added back
+        future::join_all(removed.iter().map(Device::stop)).await;
+        for device in removed {
+            info!(&self.logger, "Removed device"; "device" => device.id());
+            summary.removed.push(device.id().to_string());
+        }
+
+        for (id, device_config) in config.device_configs() {
+            let existing = self.devices().get(&id).cloned();
+            match existing {
+                None => match Device::start(id.clone(), &config, &self.services, &self.logger) {
+                    Ok(device) => {
+                        self.devices_mut().insert(id.clone(), device);
+                        info!(&self.logger, "Added device"; "device" => &id);
+                        summary.added.push(id.to_string());
+                    }
+                    Err(error) => {
+                        warn!(&self.logger, "Failed to add device: {:#}", error; "device" => &id);
+                        summary.failed.push(format!("{}: {:#}", id, error));
+                    }
+                },
+                Some(device) if device.agent_uri() != device_config.agent() => {
+                    // Stop the old device taking actions and wait for it to finish, so that it doesn't race the new one
+                    device.stop().await;
+                    match Device::start(id.clone(), &config, &self.services, &self.logger) {
+                        Ok(device) => {
+                            self.devices_mut().insert(id.clone(), device);
+                            info!(&self.logger, "Restarted device"; "device" => &id);
+                            summary.restarted.push(id.to_string());
+                        }
+                        Err(error) => {
+                            // The old device is stopped, so don't leave it in place. It's started again on the next
+                            // reload.
+                            self.devices_mut().remove(&id);
+                            warn!(&self.logger, "Failed to restart device: {:#}", error; "device" => &id);
+                            summary.failed.push(format!("{}: {:#}", id, error));
+                        }
+                    }
+                }
+                Some(device) => {
+                    if device
+                        .reconfigure(DeviceSettings::new(&config, device_config))
+                        .await
+                    {
+                        info!(&self.logger, "Updated device"; "device" => &id);
+                        summary.updated.push(id.to_string());
+                    }
+                }
+            }
+        }
+
+        *self
+            .groups
+            .write()
+            .expect("Thread panicked with groups lock") = group::groups(&config);
+        self.scheduler.configure(&config);
+
+        *current = config;
+        Ok(summary)
+    }
+
+    fn devices(&self) -> RwLockReadGuard<'_, HashMap<DeviceId, Device>> {
+        self.devices
+            .read()
+            .expect("Thread panicked with devices lock")
+    }
+
+    fn devices_mut(&self) -> RwLockWriteGuard<'_, HashMap<DeviceId, Device>> {
+        self.devices
+            .write()
+            .expect("Thread panicked with devices lock")
+    }
+}
@@ -0,0 +1,95 @@
+//! RPC interface used by agents to forward requests made locally on a device.
+
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use slog::{info, Logger};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use samwise_proto::controller_server::{Controller, ControllerServer};
+use samwise_proto::{
+    ListTargetsRequest, ListTargetsResponse, SwitchRequest, SwitchResponse, Target,
+};
+
+use crate::device::{Action, Device, Devices, Source};
+use crate::id::DeviceId;
+use crate::queue::Busy;
+
+struct ControllerImpl {
+    logger: Logger,
+    devices: Devices,
+}
+
+impl ControllerImpl {
+    fn device(&self, id: String) -> Result<Device, Status> {
+        let id = DeviceId::new(id);
+        let devices = self
+            .devices
+            .read()
+            .expect("Thread panicked with devices lock");
+        match devices.get(&id) {
+            Some(device) => Ok(device.clone()),
+            None => Err(Status::not_found(format!("No such device: {}", id))),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Controller for ControllerImpl {
+    async fn switch(
+        &self,
+        request: Request<SwitchRequest>,
+    ) -> Result<Response<SwitchResponse>, Status> {
+        let request = request.into_inner();
+        let mut device = self.device(request.device)?;
+        let target = match device.resolve_target(&request.target) {
+            Some(target) => target,
+            None => {
+                return Err(Status::not_found(format!(
+                    "No such target: {}",
+                    request.target
+                )))
+            }
+        };
+
+        info!(&self.logger, "Agent requested switch"; "device" => device.id(), "target" => %target);
+        device
+            .action(Action::Run(target), Source::Agent)
+            .await
+            .map_err(|error| match error.downcast_ref::<Busy>() {
+                Some(busy) => Status::aborted(busy.to_string()),
+                None => Status::unavailable(error.to_string()),
+            })?;
+        Ok(Response::new(SwitchResponse {}))
+    }
+
+    async fn list_targets(
+        &self,
+        request: Request<ListTargetsRequest>,
+    ) -> Result<Response<ListTargetsResponse>, Status> {
+        let device = self.device(request.into_inner().device)?;
+        let targets = device
+            .targets()
+            .into_iter()
+            .filter(|(_, target)| !target.hidden())
+            .map(|(id, target)| Target {
+                display_name: target.display_name().unwrap_or(id.as_string()).to_string(),
+                description: target.description().unwrap_or_default().to_string(),
+                icon: target.icon().unwrap_or_default().to_string(),
+                aliases: target.aliases().to_vec(),
+                id: id.into(),
+            })
+            .collect();
+        Ok(Response::new(ListTargetsResponse { targets }))
+    }
+}
+
+/// Serves the RPC interface for agents
+pub async fn serve(logger: Logger, devices: Devices, addr: SocketAddr) -> Result<()> {
+    Server::builder()
+        .add_service(ControllerServer::new(ControllerImpl { logger, devices }))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,604 @@
+//! Actions performed on devices at scheduled times, either recurring from the configuration or one-off from the API
+
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, FixedOffset, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use cron::Schedule;
+use serde::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+use tokio::sync::mpsc;
+use tokio::time;
+
+use crate::config::{ActionName, Configuration};
+use crate::device::{Action, Device, Devices, Source};
+use crate::id::{DeviceId, TargetId};
+use crate::store::{self, StateStore};
+
+/// Longest to sleep between checking schedules, so that changes to the system clock are noticed
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// How late a run can be performed before it counts as missed, to allow for the scheduler waking up late
+const LATE_TOLERANCE: Duration = Duration::from_secs(60);
+
+/// Name of the file in the state directory which schedules are saved to
+const STATE_FILE: &str = "schedules.json";
+
+/// Parses the name of a time zone, such as `America/New_York`.
+pub fn parse_timezone(name: &str) -> Result<Tz> {
+    name.parse()
+        .map_err(|error: String| anyhow!(error))
+        .with_context(|| format!("Unknown time zone `{}`", name))
+}
+
+/// Parses a cron expression, with fields for seconds, minutes, hours, day of month, month, day of week, and optionally
+/// year.
+pub fn parse_cron(expression: &str) -> Result<Schedule> {
+    Schedule::from_str(expression)
+        .map_err(|error| anyhow!("{}", error))
+        .with_context(|| format!("Invalid cron expression `{}`", expression))
+}
+
+/// Converts a scheduled action into the action to perform on the device.
+pub fn action(name: ActionName, target: Option<&str>) -> Result<Action> {
+    let action = match name {
+        ActionName::Run => match target {
+            Some(target) => return Ok(Action::Run(TargetId::new(target))),
+            None => bail!("The run action needs a target"),
+        },
+        ActionName::Boot => Action::Boot,
+        ActionName::Reboot => Action::Reboot,
+        ActionName::Suspend => Action::Suspend,
+        ActionName::Shutdown => Action::ShutDown,
+        ActionName::Maintain => Action::Maintain,
+        ActionName::DisplayOn => Action::DisplayOn,
+        ActionName::DisplayOff => Action::DisplayOff,
+    };
+    if target.is_some() {
+        bail!("Only the run action takes a target");
+    }
+    Ok(action)
+}
+
+/// A schedule from the configuration
+struct Recurring {
+    device: DeviceId,
+    action: Action,
+    cron: String,
+    schedule: Schedule,
+    timezone: Tz,
+    catch_up: Duration,
+}
+
+impl Recurring {
+    /// The first run strictly after `after`.
+    fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.schedule
+            .after(&after.with_timezone(&self.timezone))
+            .next()
+            .map(|next| next.with_timezone(&Utc))
+    }
+}
+
+/// An action scheduled once through the API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OneOff {
+    device: DeviceId,
+    action: ActionName,
+    target: Option<String>,
+    at: DateTime<Utc>,
+}
+
+/// What's saved to the state directory, so that schedules pick up where they left off after a restart
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct SavedSchedules {
+    /// Time up to which each recurring schedule has been run or skipped
+    handled: BTreeMap<String, DateTime<Utc>>,
+    one_offs: BTreeMap<String, OneOff>,
+    next_id: u64,
+}
+
+struct SchedulerInner {
+    recurring: BTreeMap<String, Recurring>,
+    saved: SavedSchedules,
+    /// Whether `saved` has changed since it was last written
+    dirty: bool,
+}
+
+/// A scheduled action and when it will next run
+#[derive(Debug, Clone, Serialize)]
+pub struct ScheduledAction {
+    pub name: String,
+    pub device: DeviceId,
+    pub action: String,
+    /// Cron expression, for recurring schedules
+    pub cron: Option<String>,
+    pub timezone: Option<String>,
+    /// Whether the action was scheduled once through the API, rather than configured
+    pub one_off: bool,
+    pub next: Option<DateTime<FixedOffset>>,
+    pub last_handled: Option<DateTime<Utc>>,
+}
+
+/// Runs scheduled actions through the devices' normal action queues
+#[derive(Clone)]
+pub struct Scheduler {
+    logger: Logger,
+    path: Option<PathBuf>,
+    inner: Arc<Mutex<SchedulerInner>>,
+    changed_tx: mpsc::Sender<()>,
+}
+
+impl Scheduler {
+    /// Starts running the schedules in `config`, along with any one-off actions saved from before a restart.
+    pub fn start(
+        logger: Logger,
+        config: &Configuration,
+        store: &StateStore,
+        devices: Devices,
+    ) -> Scheduler {
+        let path = store.file(STATE_FILE);
+        // The controller starts synchronously, and this is only a single small file
+        let saved = match path.as_ref().map(std::fs::read) {
+            Some(Ok(contents)) => match serde_json::from_slice(&contents) {
+                Ok(saved) => saved,
+                Err(error) => {
+                    warn!(&logger, "Ignoring invalid schedule state file: {}", error);
+                    SavedSchedules::default()
+                }
+            },
+            Some(Err(error)) if error.kind() != ErrorKind::NotFound => {
+                warn!(&logger, "Could not read schedule state file: {}", error);
+                SavedSchedules::default()
+            }
+            _ => SavedSchedules::default(),
+        };
+
+        // Only one wakeup needs to be pending, since the scheduler checks everything each time
+        let (changed_tx, changed_rx) = mpsc::channel(1);
+        let scheduler = Scheduler {
+            logger,
+            path,
+            inner: Arc::new(Mutex::new(SchedulerInner {
+                recurring: BTreeMap::new(),
+                saved,
+                dirty: false,
+            })),
+            changed_tx,
+        };
+        scheduler.configure(config);
+        tokio::spawn(scheduler.clone().run(devices, changed_rx));
+        scheduler
+    }
+
+    fn inner(&self) -> MutexGuard<'_, SchedulerInner> {
+        self.inner
+            .lock()
+            .expect("Thread panicked with scheduler mutex")
+    }
+
+    /// Wakes the scheduler to look at its schedules again.
+    fn notify(&self) {
+        // If a wakeup is already pending, the scheduler will see this change then
+        let _ = self.changed_tx.clone().try_send(());
+    }
+
+    /// Replaces the recurring schedules with those in `config`. Invalid schedules are skipped, since the configuration
+    /// is checked before it's applied.
+    pub fn configure(&self, config: &Configuration) {
+        let default_timezone = match config.timezone().map(parse_timezone) {
+            Some(Ok(timezone)) => timezone,
+            Some(Err(error)) => {
+                warn!(&self.logger, "{:#}, using UTC", error);
+                Tz::UTC
+            }
+            None => Tz::UTC,
+        };
+
+        let mut recurring = BTreeMap::new();
+        for (name, schedule_config) in config.schedules() {
+            let parsed = (|| {
+                Ok::<_, anyhow::Error>(Recurring {
+                    device: schedule_config.device(),
+                    action: action(schedule_config.action(), schedule_config.target())?,
+                    cron: schedule_config.cron().to_string(),
+                    schedule: parse_cron(schedule_config.cron())?,
+                    timezone: match schedule_config.timezone() {
+                        Some(timezone) => parse_timezone(timezone)?,
+                        None => default_timezone,
+                    },
+                    catch_up: schedule_config.catch_up(),
+                })
+            })();
+            match parsed {
+                Ok(schedule) => {
+                    recurring.insert(name.to_string(), schedule);
+                }
+                Err(error) => warn!(&self.logger, "Skipping schedule {}: {:#}", name, error),
+            }
+        }
+
+        let mut inner = self.inner();
+        let now = Utc::now();
+        // Newly added schedules start from now, rather than catching up on runs from before they existed
+        let handled = &mut inner.saved.handled;
+        handled.retain(|name, _| recurring.contains_key(name));
+        for name in recurring.keys() {
+            handled.entry(name.clone()).or_insert(now);
+        }
+        inner.recurring = recurring;
+        inner.dirty = true;
+        drop(inner);
+        self.notify();
+    }
+
+    /// Schedules `action` to run on `device` once, at `at`. Fails if `at` has already passed, or the target isn't one
+    /// of the device's.
+    pub fn schedule_once(
+        &self,
+        device: &Device,
+        action_name: ActionName,
+        target: Option<String>,
+        at: DateTime<Utc>,
+    ) -> Result<ScheduledAction> {
+        if at < Utc::now() {
+            bail!("{} is in the past", at.to_rfc3339());
+        }
+        action(action_name, target.as_deref())?;
+        // Resolve aliases up front, so that the action can't fail for an unknown target when it runs
+        let target = match target {
+            Some(name) => match device.resolve_target(&name) {
+                Some(target) => Some(target.as_string().to_string()),
+                None => bail!(
+                    "Target `{}` is not a configured target of `{}`",
+                    name,
+                    device.id()
+                ),
+            },
+            None => None,
+        };
+        let one_off = OneOff {
+            device: device.id().clone(),
+            action: action_name,
+            target,
+            at,
+        };
+
+        let mut inner = self.inner();
+        inner.saved.next_id += 1;
+        let name = format!("once-{}", inner.saved.next_id);
+        info!(&self.logger, "Scheduled one-off action"; "name" => &name, "device" => &one_off.device, "at" => %at);
+        inner.saved.one_offs.insert(name.clone(), one_off.clone());
+        inner.dirty = true;
+        drop(inner);
+        self.notify();
+        Ok(one_off_info(name, &one_off))
+    }
+
+    /// Cancels a one-off action. Returns false if there is no such action. Configured schedules can't be cancelled,
+    /// since they would come back when the configuration is reloaded.
+    pub fn cancel(&self, name: &str) -> Result<bool> {
+        let mut inner = self.inner();
+        if inner.recurring.contains_key(name) {
+            bail!("Schedule `{}` is in the configuration file", name);
+        }
+        let cancelled = inner.saved.one_offs.remove(name).is_some();
+        if cancelled {
+            info!(&self.logger, "Cancelled one-off action"; "name" => name);
+            inner.dirty = true;
+            drop(inner);
+            self.notify();
+        }
+        Ok(cancelled)
+    }
+
+    /// Every scheduled action, ordered by when it next runs.
+    pub fn list(&self) -> Vec<ScheduledAction> {
+        let inner = self.inner();
+        let mut actions: Vec<ScheduledAction> = inner
+            .recurring
+            .iter()
+            .map(|(name, schedule)| {
+                let handled = inner.saved.handled.get(name).copied();
+                let next = schedule
+                    .next_after(handled.unwrap_or_else(Utc::now))
+                    .map(|next| {
+                        let next = next.with_timezone(&schedule.timezone);
+                        next.with_timezone(&next.offset().fix())
+                    });
+                ScheduledAction {
+                    name: name.clone(),
+                    device: schedule.device.clone(),
+                    action: schedule.action.to_string(),
+                    cron: Some(schedule.cron.clone()),
+                    timezone: Some(schedule.timezone.name().to_string()),
+                    one_off: false,
+                    next,
+                    last_handled: handled,
+                }
+            })
+            .chain(
+                inner
+                    .saved
+                    .one_offs
+                    .iter()
+                    .map(|(name, one_off)| one_off_info(name.clone(), one_off)),
+            )
+            .collect();
+        actions.sort_by_key(|action| action.next);
+        actions
+    }
+
+    /// Takes every action which is due to run at `now`, marking recurring schedules as handled up to `now` and removing
+    /// one-off actions. Runs missed by more than their catch-up window are skipped.
+    fn take_due(&self, now: DateTime<Utc>) -> Vec<(String, DeviceId, Action)> {
+        let mut inner = self.inner();
+        let inner = &mut *inner;
+        let mut due = Vec::new();
+
+        for (name, schedule) in inner.recurring.iter() {
+            let handled = inner.saved.handled.entry(name.clone()).or_insert(now);
+            let next = match schedule.next_after(*handled) {
+                Some(next) if next <= now => next,
+                _ => continue,
+            };
+
+            // Only run if a run falls within the window, even if earlier ones were missed
+            let window = chrono::Duration::from_std(LATE_TOLERANCE + schedule.catch_up)
+                .unwrap_or_else(|_| chrono::Duration::zero());
+            let window_start = (now - window).max(*handled);
+            let in_window = window_start == next
+                || schedule
+                    .next_after(window_start)
+                    .map_or(false, |run| run <= now);
+            if in_window {
+                due.push((
+                    name.clone(),
+                    schedule.device.clone(),
+                    schedule.action.clone(),
+                ));
+            } else {
+                warn!(&self.logger, "Skipping missed run"; "name" => name, "missed" => %next);
+            }
+            *handled = now;
+            inner.dirty = true;
+        }
+
+        let ready: Vec<String> = inner
+            .saved
+            .one_offs
+            .iter()
+            .filter(|(_, one_off)| one_off.at <= now)
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in ready {
+            let one_off = inner.saved.one_offs.remove(&name).unwrap();
+            inner.dirty = true;
+            let late = (now - one_off.at).to_std().unwrap_or_default();
+            if late > LATE_TOLERANCE {
+                warn!(&self.logger, "Skipping missed run"; "name" => &name, "missed" => %one_off.at);
+                continue;
+            }
+            // Already validated when it was scheduled
+            if let Ok(action) = action(one_off.action, one_off.target.as_deref()) {
+                due.push((name, one_off.device, action));
+            }
+        }
+
+        due
+    }
+
+    /// When the next action is due to run, if there is one.
+    fn next_run(&self) -> Option<DateTime<Utc>> {
+        let inner = self.inner();
+        let recurring = inner.recurring.iter().filter_map(|(name, schedule)| {
+            let handled = inner.saved.handled.get(name).copied()?;
+            schedule.next_after(handled)
+        });
+        let one_offs = inner.saved.one_offs.values().map(|one_off| one_off.at);
+        recurring.chain(one_offs).min()
+    }
+
+    /// Writes the schedule state to the state directory, if it changed.
+    async fn save(&self) {
+        let contents = {
+            let mut inner = self.inner();
+            if !inner.dirty {
+                return;
+            }
+            inner.dirty = false;
+            serde_json::to_vec_pretty(&inner.saved)
+        };
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return,
+        };
+
+        let result = match contents {
+            Ok(contents) => store::write_atomically(path, contents).await,
+            Err(error) => Err(error.into()),
+        };
+        if let Err(error) = result {
+            warn!(&self.logger, "Could not save schedules: {:#}", error);
+        }
+    }
+
+    async fn run(self, devices: Devices, mut changed_rx: mpsc::Receiver<()>) {
+        loop {
+            let due = self.take_due(Utc::now());
+            self.save().await;
+
+            for (name, device_id, action) in due {
+                let device = devices
+                    .read()
+                    .expect("Thread panicked with devices lock")
+                    .get(&device_id)
+                    .cloned();
+                let mut device = match device {
+                    Some(device) => device,
+                    None => {
+                        warn!(&self.logger, "Scheduled device does not exist"; "name" => &name, "device" => &device_id);
+                        continue;
+                    }
+                };
+
+                info!(&self.logger, "Running scheduled action"; "name" => &name, "device" => &device_id, "action" => %action);
+                if let Err(error) = device.action(action, Source::Schedule(name.clone())).await {
+                    warn!(&self.logger, "Scheduled action failed: {:#}", error; "name" => &name, "device" => &device_id);
+                }
+            }
+
+            let sleep = match self.next_run() {
+                Some(next) => (next - Utc::now())
+                    .to_std()
+                    .unwrap_or_default()
+                    .min(MAX_SLEEP),
+                None => MAX_SLEEP,
+            };
+            tokio::select! {
+                _ = changed_rx.recv() => (),
+                _ = time::delay_for(sleep) => (),
+            }
+        }
+    }
+}
+
+fn one_off_info(name: String, one_off: &OneOff) -> ScheduledAction {
+    ScheduledAction {
+        name,
+        device: one_off.device.clone(),
+        action: action(one_off.action, one_off.target.as_deref())
+            .map(|action| action.to_string())
+            .unwrap_or_default(),
+        cron: None,
+        timezone: None,
+        one_off: true,
+        next: Some(Utc.fix().from_utc_datetime(&one_off.at.naive_utc())),
+        last_handled: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use slog::{o, Discard};
+
+    use super::*;
+
+    /// A scheduler with an hourly schedule that has been handled up to `handled`, and one-off actions at each of
+    /// `one_offs`.
+    fn scheduler(
+        catch_up: Duration,
+        handled: DateTime<Utc>,
+        one_offs: &[DateTime<Utc>],
+    ) -> Scheduler {
+        let device = DeviceId::new("test");
+        let mut saved = SavedSchedules::default();
+        saved.handled.insert("hourly".to_string(), handled);
+        for (index, at) in one_offs.iter().enumerate() {
+            let one_off = OneOff {
+                device: device.clone(),
+                action: ActionName::Boot,
+                target: None,
+                at: *at,
+            };
+            saved
+                .one_offs
+                .insert(format!("once-{}", index + 1), one_off);
+        }
+
+        let mut recurring = BTreeMap::new();
+        recurring.insert(
+            "hourly".to_string(),
+            Recurring {
+                device,
+                action: Action::Suspend,
+                cron: "0 0 * * * *".to_string(),
+                schedule: parse_cron("0 0 * * * *").unwrap(),
+                timezone: Tz::UTC,
+                catch_up,
+            },
+        );
+
+        let (changed_tx, _) = mpsc::channel(1);
+        Scheduler {
+            logger: Logger::root(Discard, o!()),
+            path: None,
+            inner: Arc::new(Mutex::new(SchedulerInner {
+                recurring,
+                saved,
+                dirty: false,
+            })),
+            changed_tx,
+        }
+    }
+
+    fn at(hour: u32, minute: u32, second: u32) -> DateTime<Utc> {
+        Utc.ymd(2020, 6, 1).and_hms(hour, minute, second)
+    }
+
+    fn due_names(scheduler: &Scheduler, now: DateTime<Utc>) -> Vec<String> {
+        scheduler
+            .take_due(now)
+            .into_iter()
+            .map(|(name, _, _)| name)
+            .collect()
+    }
+
+    #[test]
+    fn runs_on_time() {
+        let scheduler = scheduler(Duration::from_secs(0), at(10, 0, 0), &[]);
+        assert!(due_names(&scheduler, at(10, 59, 59)).is_empty());
+        assert_eq!(due_names(&scheduler, at(11, 0, 30)), vec!["hourly"]);
+        // Only runs once
+        assert!(due_names(&scheduler, at(11, 0, 40)).is_empty());
+        assert_eq!(scheduler.next_run(), Some(at(12, 0, 0)));
+    }
+
+    #[test]
+    fn skips_missed_run_without_catch_up() {
+        let scheduler = scheduler(Duration::from_secs(0), at(10, 0, 0), &[]);
+        assert!(due_names(&scheduler, at(11, 5, 0)).is_empty());
+        assert!(due_names(&scheduler, at(11, 6, 0)).is_empty());
+        assert_eq!(due_names(&scheduler, at(12, 0, 10)), vec!["hourly"]);
+    }
+
+    #[test]
+    fn catches_up_within_window() {
+        let on_time = scheduler(Duration::from_secs(10 * 60), at(10, 0, 0), &[]);
+        assert_eq!(due_names(&on_time, at(11, 5, 0)), vec!["hourly"]);
+
+        let too_late = scheduler(Duration::from_secs(10 * 60), at(10, 0, 0), &[]);
+        assert!(due_names(&too_late, at(11, 15, 0)).is_empty());
+    }
+
+    #[test]
+    fn catches_up_once_for_several_missed_runs() {
+        // The 11:00 and 12:00 runs were missed, but 13:00 is within the window
+        let scheduler = scheduler(Duration::from_secs(60 * 60), at(10, 0, 0), &[]);
+        assert_eq!(due_names(&scheduler, at(13, 30, 0)), vec!["hourly"]);
+        assert!(due_names(&scheduler, at(13, 31, 0)).is_empty());
+    }
+
+    #[test]
+    fn one_offs_run_once_unless_missed() {
+        let scheduler = scheduler(
+            Duration::from_secs(0),
+            at(10, 0, 0),
+            &[at(10, 30, 0), at(10, 40, 0)],
+        );
+        assert!(due_names(&scheduler, at(10, 29, 0)).is_empty());
+        assert_eq!(scheduler.next_run(), Some(at(10, 30, 0)));
+        assert_eq!(due_names(&scheduler, at(10, 30, 10)), vec!["once-1"]);
+
+        // Too late, so it's dropped without running
+        assert!(due_names(&scheduler, at(10, 45, 0)).is_empty());
+        assert!(scheduler.list().iter().all(|action| !action.one_off));
+    }
+}
@@ -1,36 +1,199 @@
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use slog::{error, Logger};
 use warp::http::StatusCode;
 use warp::reject::Reject;
 use warp::{Filter, Rejection, Reply};
 
-use crate::device::{Action, Device, State};
-use crate::id::{TargetId, DeviceId};
+use crate::agent::{Activity, DisplayState, Hold};
+use crate::config::{ActionName, TargetConfiguration};
+use crate::device::{Action, Device, Devices, Source, State};
+use crate::group::{self, Groups, Strategy};
+use crate::history::{Event, EventKind};
+use crate::id::{ActionId, DeviceId, TargetId};
+use crate::queue::Busy;
+use crate::reload::Reloader;
+use crate::schedule::Scheduler;
+use crate::stats::Phase;
+use crate::tracker::{ActionStatus, ActionTracker};
+
+/// Number of history events to return if the client doesn't give a limit
+const DEFAULT_HISTORY_LIMIT: usize = 100;
 
 // Request and response types
 
 #[derive(Serialize)]
-#[serde(tag = "state", rename_all = "lowercase")]
-enum StatusResponse {
+struct StatusResponse {
+    #[serde(flatten)]
+    state: StateResponse,
+    holds: Vec<HoldResponse>,
+    display: DisplayResponse,
+    reboot_required: bool,
+    updates_pending: bool,
+    activity: Option<ActivityResponse>,
+    last_target: Option<TargetId>,
+    last_action: Option<String>,
+    last_action_started: Option<DateTime<Utc>>,
+    last_action_finished: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+}
+
+impl From<&Device> for StatusResponse {
+    fn from(device: &Device) -> Self {
+        let report = device.latest_report();
+        let record = device.latest_record();
+        StatusResponse {
+            state: device.latest_state().into(),
+            holds: report.holds.into_iter().map(HoldResponse::from).collect(),
+            display: report.display.into(),
+            reboot_required: report.reboot_required,
+            updates_pending: report.updates_pending,
+            activity: report.activity.map(ActivityResponse::from),
+            last_target: record.last_target,
+            last_action: record.last_action,
+            last_action_started: record.last_action_started,
+            last_action_finished: record.last_action_finished,
+            last_error: record.last_error,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum StateResponse {
     Off,
     Running { target: String },
+    Suspended { target: String },
     Unknown,
+    Unreachable,
+    Booting { target: Option<String> },
+    Rebooting { from: String, to: String },
+    ShuttingDown,
+    Suspending,
 }
 
-impl From<State> for StatusResponse {
+impl From<State> for StateResponse {
     fn from(state: State) -> Self {
         match state {
-            State::Off => StatusResponse::Off,
-            State::Running(target) => StatusResponse::Running {
+            State::Off => StateResponse::Off,
+            State::Running(target) => StateResponse::Running {
                 target: target.into(),
             },
-            State::Unknown => StatusResponse::Unknown,
+            State::Suspended(target) => StateResponse::Suspended {
+                target: target.into(),
+            },
+            State::Unknown => StateResponse::Unknown,
+            State::Unreachable => StateResponse::Unreachable,
+            State::Booting { target } => StateResponse::Booting {
+                target: target.map(String::from),
+            },
+            State::Rebooting { from, to } => StateResponse::Rebooting {
+                from: from.into(),
+                to: to.into(),
+            },
+            State::ShuttingDown => StateResponse::ShuttingDown,
+            State::Suspending => StateResponse::Suspending,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum DisplayResponse {
+    Unknown,
+    On,
+    Off,
+}
+
+impl From<DisplayState> for DisplayResponse {
+    fn from(state: DisplayState) -> Self {
+        match state {
+            DisplayState::Unknown => DisplayResponse::Unknown,
+            DisplayState::On => DisplayResponse::On,
+            DisplayState::Off => DisplayResponse::Off,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HoldResponse {
+    id: u64,
+    reason: String,
+    /// Seconds until the hold is automatically released
+    expires_in: u64,
+}
+
+impl From<Hold> for HoldResponse {
+    fn from(hold: Hold) -> Self {
+        HoldResponse {
+            id: hold.id,
+            reason: hold.reason,
+            expires_in: hold
+                .expires
+                .saturating_duration_since(Instant::now())
+                .as_secs(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ActivityResponse {
+    /// Seconds since the last local user input
+    idle: u64,
+    /// Login sessions, if the agent could list them
+    sessions: Option<Vec<SessionResponse>>,
+}
+
+impl From<Activity> for ActivityResponse {
+    fn from(activity: Activity) -> Self {
+        ActivityResponse {
+            idle: activity.idle.as_secs(),
+            sessions: activity.sessions.map(|sessions| {
+                sessions
+                    .into_iter()
+                    .map(|session| SessionResponse {
+                        user: session.user,
+                        host: session.host,
+                    })
+                    .collect()
+            }),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    user: String,
+    /// Host a remote session is connected from
+    host: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TargetResponse {
+    id: String,
+    /// Human-readable name, which defaults to the ID
+    display_name: String,
+    description: Option<String>,
+    icon: Option<String>,
+    aliases: Vec<String>,
+}
+
+impl From<(TargetId, TargetConfiguration)> for TargetResponse {
+    fn from((id, target): (TargetId, TargetConfiguration)) -> Self {
+        TargetResponse {
+            display_name: target.display_name().unwrap_or(id.as_string()).to_string(),
+            description: target.description().map(str::to_string),
+            icon: target.icon().map(str::to_string),
+            aliases: target.aliases().to_vec(),
+            id: id.into(),
         }
     }
 }
@@ -40,6 +203,26 @@ struct ActionResponse {
     success: bool,
     device: String,
     action: String,
+    /// ID to follow the action's progress with
+    id: ActionId,
+}
+
+#[derive(Serialize)]
+struct GroupActionResponse {
+    /// Whether the action was started on every device
+    success: bool,
+    group: String,
+    action: String,
+    devices: Vec<DeviceActionResponse>,
+}
+
+#[derive(Serialize)]
+struct DeviceActionResponse {
+    device: String,
+    success: bool,
+    /// ID to follow the device's action with
+    id: Option<ActionId>,
+    error: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -47,10 +230,150 @@ struct RunRequest {
     target: String,
 }
 
+#[derive(Deserialize)]
+struct HoldRequest {
+    reason: String,
+    /// Seconds to hold for
+    ttl: u64,
+}
+
+/// A one-off action to schedule
+#[derive(Deserialize)]
+struct ScheduleRequest {
+    device: String,
+    action: ActionName,
+    /// Target to run, for the run action
+    target: Option<String>,
+    at: DateTime<Utc>,
+}
+
+/// Query parameters for a device's duration statistics. By default, covers the device's whole history.
+#[derive(Deserialize)]
+struct StatsQuery {
+    since: Option<DateTime<Utc>>,
+}
+
+/// Query parameters for a device's history. By default, returns the most recent events.
+#[derive(Deserialize)]
+struct HistoryQuery {
+    since: Option<DateTime<Utc>>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct EventResponse {
+    time: DateTime<Utc>,
+    #[serde(flatten)]
+    kind: EventKindResponse,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum EventKindResponse {
+    StateChanged {
+        #[serde(flatten)]
+        state: StateResponse,
+    },
+    ActionReceived {
+        id: ActionId,
+        action: String,
+        source: String,
+    },
+    ActionStarted {
+        id: ActionId,
+        action: String,
+    },
+    Step {
+        id: ActionId,
+        step: String,
+    },
+    ActionFinished {
+        id: ActionId,
+        action: String,
+        status: ActionStatus,
+        error: Option<String>,
+    },
+    Measured {
+        phase: Phase,
+        target: TargetId,
+        seconds: f64,
+    },
+}
+
+impl From<Event> for EventResponse {
+    fn from(event: Event) -> Self {
+        let kind = match event.kind {
+            EventKind::StateChanged { state } => EventKindResponse::StateChanged {
+                state: state.into(),
+            },
+            EventKind::ActionReceived { id, action, source } => {
+                EventKindResponse::ActionReceived { id, action, source }
+            }
+            EventKind::ActionStarted { id, action } => {
+                EventKindResponse::ActionStarted { id, action }
+            }
+            EventKind::Step { id, step } => EventKindResponse::Step { id, step },
+            EventKind::ActionFinished {
+                id,
+                action,
+                status,
+                error,
+            } => EventKindResponse::ActionFinished {
+                id,
+                action,
+                status,
+                error,
+            },
+            EventKind::Measured {
+                phase,
+                target,
+                seconds,
+            } => EventKindResponse::Measured {
+                phase,
+                target,
+                seconds,
+            },
+        };
+        EventResponse {
+            time: event.time,
+            kind,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum StrategyName {
+    All,
+    Rolling,
+}
+
+/// Query parameters selecting how to act on a group. Defaults to acting on all devices at once, and rolling defaults
+/// to one device at a time.
+#[derive(Deserialize)]
+struct StrategyQuery {
+    strategy: Option<StrategyName>,
+    max_in_flight: Option<usize>,
+}
+
+impl From<StrategyQuery> for Strategy {
+    fn from(query: StrategyQuery) -> Self {
+        match query.strategy {
+            None | Some(StrategyName::All) => Strategy::All,
+            Some(StrategyName::Rolling) => Strategy::Rolling {
+                max_in_flight: query.max_in_flight.unwrap_or(1),
+            },
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct ErrorResponse {
     success: bool,
     error: String,
+    /// The action a device is busy with, if the request was rejected because of it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_progress: Option<String>,
 }
 
 // Custom Warp rejections
@@ -63,16 +386,60 @@ struct ActionFailed {
 
 impl Reject for ActionFailed {}
 
-/// Error handler aware of ActionFailed rejections
+#[derive(Debug)]
+struct ReloadFailed {
+    error: Error,
+}
+
+impl Reject for ReloadFailed {}
+
+#[derive(Debug)]
+struct ScheduleFailed {
+    error: Error,
+}
+
+impl Reject for ScheduleFailed {}
+
+#[derive(Debug)]
+struct UnknownTarget {
+    device: DeviceId,
+    target: String,
+}
+
+impl Reject for UnknownTarget {}
+
+/// Error handler aware of ActionFailed, ReloadFailed, ScheduleFailed, and UnknownTarget rejections
 async fn handle_error(logger: Logger, err: Rejection) -> Result<impl Reply, Infallible> {
+    let mut in_progress = None;
     let (code, error) = if err.is_not_found() {
         (StatusCode::NOT_FOUND, "Not found".to_string())
+    } else if let Some(busy) = err
+        .find::<ActionFailed>()
+        .and_then(|e| e.error.downcast_ref::<Busy>())
+    {
+        in_progress = Some(busy.in_progress.to_string());
+        (StatusCode::CONFLICT, busy.to_string())
     } else if let Some(e) = err.find::<ActionFailed>() {
         (
             StatusCode::SERVICE_UNAVAILABLE,
             format!("Operation on {} failed: {}", e.device, e.error),
         )
-    } else if let Some(_) = err.find::<warp::reject::MethodNotAllowed>() {
+    } else if let Some(e) = err.find::<ReloadFailed>() {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Reloading configuration failed: {:#}", e.error),
+        )
+    } else if let Some(e) = err.find::<ScheduleFailed>() {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Scheduling failed: {:#}", e.error),
+        )
+    } else if let Some(e) = err.find::<UnknownTarget>() {
+        (
+            StatusCode::NOT_FOUND,
+            format!("No such target for {}: {}", e.device, e.target),
+        )
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         (
             StatusCode::METHOD_NOT_ALLOWED,
             "HTTP method not allowed".to_string(),
@@ -88,16 +455,18 @@ async fn handle_error(logger: Logger, err: Rejection) -> Result<impl Reply, Infa
     let json = warp::reply::json(&ErrorResponse {
         error,
         success: false,
+        in_progress,
     });
     Ok(warp::reply::with_status(json, code))
 }
 
-/// Create a successful action reply
-fn action_success(device: &Device, action: Action) -> impl Reply {
+/// Create a reply for a successfully queued action
+fn action_success(device: &Device, action: Action, id: ActionId) -> impl Reply {
     warp::reply::json(&ActionResponse {
         device: device.id().as_string().clone(),
         success: true,
         action: action.to_string(),
+        id,
     })
 }
 
@@ -109,20 +478,177 @@ fn action_failure(device: &Device, error: Error) -> warp::Rejection {
     })
 }
 
+/// A group, resolved to its running devices
+struct Group {
+    name: String,
+    devices: Vec<Device>,
+}
+
+/// Starts an action on every device in a group, reporting the ID to follow it with for each device
+fn group_action(group: Group, action: Action, strategy: Strategy) -> impl Reply {
+    let results = group::start(&group.name, group.devices, action.clone(), strategy);
+    let devices: Vec<DeviceActionResponse> = results
+        .into_iter()
+        .map(|(device, result)| DeviceActionResponse {
+            device: device.to_string(),
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|error| format!("{:#}", error)),
+            id: result.ok(),
+        })
+        .collect();
+    warp::reply::json(&GroupActionResponse {
+        success: devices.iter().all(|device| device.success),
+        group: group.name,
+        action: action.to_string(),
+        devices,
+    })
+}
+
 /// Serves the Samwise HTTP API
-pub async fn serve(logger: Logger, devices: Arc<HashMap<DeviceId, Device>>, addr: SocketAddr) {
+pub async fn serve(
+    logger: Logger,
+    devices: Devices,
+    groups: Groups,
+    scheduler: Scheduler,
+    tracker: ActionTracker,
+    reloader: Arc<Reloader>,
+    addr: SocketAddr,
+) {
     let with_devices = warp::any().map(move || devices.clone());
+    let with_groups = warp::any().map(move || groups.clone());
+    let with_reloader = warp::any().map(move || reloader.clone());
+    let with_tracker = warp::any().map(move || tracker.clone());
+    let with_scheduler = warp::any().map(move || scheduler.clone());
+    let source = warp::addr::remote().map(Source::Api);
 
     // Base for device-scoped endpoints
     let device = warp::path("device")
         .and(warp::path::param())
-        .and(with_devices)
-        .and_then(
-            async move |device_id: String, devices: Arc<HashMap<DeviceId, Device>>| match devices
+        .and(with_devices.clone())
+        .and_then(async move |device_id: String, devices: Devices| {
+            match devices
+                .read()
+                .expect("Thread panicked with devices lock")
                 .get(&DeviceId::new(device_id))
             {
                 Some(device) => Ok(device.clone()),
                 None => Err(warp::reject::not_found()),
+            }
+        });
+
+    // Base for group-scoped endpoints
+    let group = warp::path("group")
+        .and(warp::path::param())
+        .and(with_groups)
+        .and(with_devices.clone())
+        .and_then(
+            async move |name: String, groups: Groups, devices: Devices| {
+                let groups = groups.read().expect("Thread panicked with groups lock");
+                let devices = devices.read().expect("Thread panicked with devices lock");
+                match groups.get(&name) {
+                    Some(members) => Ok(Group {
+                        devices: members
+                            .iter()
+                            .filter_map(|id| devices.get(id).cloned())
+                            .collect(),
+                        name,
+                    }),
+                    None => Err(warp::reject::not_found()),
+                }
+            },
+        );
+
+    let action_status = warp::path!("actions" / u64)
+        .and(warp::get())
+        .and(with_tracker.clone())
+        .and_then(async move |id: u64, tracker: ActionTracker| {
+            match tracker.get(ActionId::new(id)) {
+                Some(record) => Ok(warp::reply::json(&record)),
+                None => Err(warp::reject::not_found()),
+            }
+        });
+
+    let cancel_action = warp::path!("actions" / u64)
+        .and(warp::delete())
+        .and(with_tracker.clone())
+        .and(with_devices.clone())
+        .and_then(
+            async move |id: u64, tracker: ActionTracker, devices: Devices| {
+                let id = ActionId::new(id);
+                let device = tracker.get(id).and_then(|record| {
+                    devices
+                        .read()
+                        .expect("Thread panicked with devices lock")
+                        .get(&DeviceId::new(record.device))
+                        .cloned()
+                });
+                match device {
+                    Some(device) => match device.cancel(Some(id)) {
+                        Ok(id) => Ok(warp::reply::json(&tracker.get(id))),
+                        Err(error) => Err(action_failure(&device, error)),
+                    },
+                    None => Err(warp::reject::not_found()),
+                }
+            },
+        );
+
+    let list_schedules = warp::path!("schedules")
+        .and(warp::get())
+        .and(with_scheduler.clone())
+        .map(|scheduler: Scheduler| warp::reply::json(&scheduler.list()));
+
+    let add_schedule = warp::path!("schedules")
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json::<ScheduleRequest>())
+        .and(with_scheduler.clone())
+        .and(with_devices)
+        .and_then(
+            async move |request: ScheduleRequest, scheduler: Scheduler, devices: Devices| {
+                let id = DeviceId::new(request.device);
+                let device = devices
+                    .read()
+                    .expect("Thread panicked with devices lock")
+                    .get(&id)
+                    .cloned();
+                let device = match device {
+                    Some(device) => device,
+                    None => {
+                        let error = anyhow!("Device `{}` is not configured", id);
+                        return Err(warp::reject::custom(ScheduleFailed { error }));
+                    }
+                };
+                match scheduler.schedule_once(&device, request.action, request.target, request.at) {
+                    Ok(scheduled) => Ok(warp::reply::with_status(
+                        warp::reply::json(&scheduled),
+                        StatusCode::CREATED,
+                    )),
+                    Err(error) => Err(warp::reject::custom(ScheduleFailed { error })),
+                }
+            },
+        );
+
+    let cancel_schedule = warp::path!("schedules" / String)
+        .and(warp::delete())
+        .and(with_scheduler)
+        .and_then(
+            async move |name: String, scheduler: Scheduler| match scheduler.cancel(&name) {
+                Ok(true) => Ok(warp::reply::with_status(
+                    warp::reply(),
+                    StatusCode::NO_CONTENT,
+                )),
+                Ok(false) => Err(warp::reject::not_found()),
+                Err(error) => Err(warp::reject::custom(ScheduleFailed { error })),
+            },
+        );
+
+    let reload = warp::path!("admin" / "reload")
+        .and(warp::post())
+        .and(with_reloader)
+        .and_then(
+            async move |reloader: Arc<Reloader>| match reloader.reload().await {
+                Ok(summary) => Ok(warp::reply::json(&summary)),
+                Err(error) => Err(warp::reject::custom(ReloadFailed { error })),
             },
         );
 
@@ -131,7 +657,21 @@ pub async fn serve(logger: Logger, devices: Arc<HashMap<DeviceId, Device>>, addr
         .and(warp::path("status"))
         .and(warp::get())
         .map(|device: Device| {
-            let response: StatusResponse = device.latest_state().into();
+            let response = StatusResponse::from(&device);
+            warp::reply::json(&response)
+        });
+
+    let targets = device
+        .clone()
+        .and(warp::path("targets"))
+        .and(warp::get())
+        .map(|device: Device| {
+            let response: Vec<TargetResponse> = device
+                .targets()
+                .into_iter()
+                .filter(|(_, target)| !target.hidden())
+                .map(TargetResponse::from)
+                .collect();
             warp::reply::json(&response)
         });
 
@@ -139,52 +679,264 @@ pub async fn serve(logger: Logger, devices: Arc<HashMap<DeviceId, Device>>, addr
         .clone()
         .and(warp::path("suspend"))
         .and(warp::post())
-        .and_then(
-            async move |mut device: Device| match device.action(Action::Suspend).await {
-                Ok(_) => Ok(action_success(&device, Action::Suspend)),
+        .and(source)
+        .and_then(async move |mut device: Device, source: Source| {
+            match device.action(Action::Suspend, source).await {
+                Ok(id) => Ok(action_success(&device, Action::Suspend, id)),
                 Err(error) => Err(action_failure(&device, error)),
-            },
-        );
+            }
+        });
 
     let shutdown = device
         .clone()
         .and(warp::path("shutdown"))
         .and(warp::post())
+        .and(source)
+        .and_then(async move |mut device: Device, source: Source| {
+            match device.action(Action::ShutDown, source).await {
+                Ok(id) => Ok(action_success(&device, Action::ShutDown, id)),
+                Err(error) => Err(action_failure(&device, error)),
+            }
+        });
+
+    let reboot = device
+        .clone()
+        .and(warp::path("reboot"))
+        .and(warp::post())
+        .and(source)
+        .and_then(async move |mut device: Device, source: Source| {
+            match device.action(Action::Reboot, source).await {
+                Ok(id) => Ok(action_success(&device, Action::Reboot, id)),
+                Err(error) => Err(action_failure(&device, error)),
+            }
+        });
+
+    let cancel = device
+        .clone()
+        .and(warp::path("cancel"))
+        .and(warp::post())
+        .and(with_tracker)
         .and_then(
-            async move |mut device: Device| match device.action(Action::ShutDown).await {
-                Ok(_) => Ok(action_success(&device, Action::ShutDown)),
+            async move |device: Device, tracker: ActionTracker| match device.cancel(None) {
+                Ok(id) => Ok(warp::reply::json(&tracker.get(id))),
                 Err(error) => Err(action_failure(&device, error)),
             },
         );
 
-    let reboot = device
+    let boot = device
         .clone()
-        .and(warp::path("reboot"))
+        .and(warp::path("boot"))
+        .and(warp::post())
+        .and(source)
+        .and_then(async move |mut device: Device, source: Source| {
+            match device.action(Action::Boot, source).await {
+                Ok(id) => Ok(action_success(&device, Action::Boot, id)),
+                Err(error) => Err(action_failure(&device, error)),
+            }
+        });
+
+    let maintain = device
+        .clone()
+        .and(warp::path("maintenance"))
+        .and(warp::post())
+        .and(source)
+        .and_then(async move |mut device: Device, source: Source| {
+            match device.action(Action::Maintain, source).await {
+                Ok(id) => Ok(action_success(&device, Action::Maintain, id)),
+                Err(error) => Err(action_failure(&device, error)),
+            }
+        });
+
+    let maintenance = device
+        .clone()
+        .and(warp::path("maintenance"))
+        .and(warp::get())
+        .map(|device: Device| warp::reply::json(&device.latest_maintenance()));
+
+    let history = device
+        .clone()
+        .and(warp::path("history"))
+        .and(warp::get())
+        .and(warp::query::<HistoryQuery>())
+        .map(|device: Device, query: HistoryQuery| {
+            let response: Vec<EventResponse> = device
+                .history(query.since, query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT))
+                .into_iter()
+                .map(EventResponse::from)
+                .collect();
+            warp::reply::json(&response)
+        });
+
+    let stats = device
+        .clone()
+        .and(warp::path("stats"))
+        .and(warp::get())
+        .and(warp::query::<StatsQuery>())
+        .map(|device: Device, query: StatsQuery| warp::reply::json(&device.stats(query.since)));
+
+    let display_on = device
+        .clone()
+        .and(warp::path!("display" / "on"))
+        .and(warp::post())
+        .and(source)
+        .and_then(async move |mut device: Device, source: Source| {
+            match device.action(Action::DisplayOn, source).await {
+                Ok(id) => Ok(action_success(&device, Action::DisplayOn, id)),
+                Err(error) => Err(action_failure(&device, error)),
+            }
+        });
+
+    let display_off = device
+        .clone()
+        .and(warp::path!("display" / "off"))
+        .and(warp::post())
+        .and(source)
+        .and_then(async move |mut device: Device, source: Source| {
+            match device.action(Action::DisplayOff, source).await {
+                Ok(id) => Ok(action_success(&device, Action::DisplayOff, id)),
+                Err(error) => Err(action_failure(&device, error)),
+            }
+        });
+
+    let run = device
+        .clone()
+        .and(warp::path("run"))
+        .and(warp::post())
+        .and(warp::body::content_length_limit(1024)) // Should be more than enough
+        .and(warp::body::json::<RunRequest>())
+        .and(source)
+        .and_then(
+            async move |mut device: Device, request: RunRequest, source: Source| {
+                let target = match device.resolve_target(&request.target) {
+                    Some(target) => target,
+                    None => {
+                        return Err(warp::reject::custom(UnknownTarget {
+                            device: device.id().clone(),
+                            target: request.target,
+                        }))
+                    }
+                };
+                let action = Action::Run(target);
+                match device.action(action.clone(), source).await {
+                    Ok(id) => Ok(action_success(&device, action, id)),
+                    Err(error) => Err(action_failure(&device, error)),
+                }
+            },
+        );
+
+    let hold = device
+        .clone()
+        .and(warp::path("hold"))
+        .and(warp::path::end())
         .and(warp::post())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json::<HoldRequest>())
+        .and_then(async move |mut device: Device, request: HoldRequest| {
+            match device
+                .hold(request.reason, Duration::from_secs(request.ttl))
+                .await
+            {
+                Ok(hold) => Ok(warp::reply::json(&HoldResponse::from(hold))),
+                Err(error) => Err(action_failure(&device, error)),
+            }
+        });
+
+    let release = device
+        .clone()
+        .and(warp::path("hold"))
+        .and(warp::path::param())
+        .and(warp::delete())
         .and_then(
-            async move |mut device: Device| match device.action(Action::Reboot).await {
-                Ok(_) => Ok(action_success(&device, Action::Reboot)),
+            async move |mut device: Device, id: u64| match device.release(id).await {
+                Ok(_) => Ok(warp::reply::with_status(
+                    warp::reply(),
+                    StatusCode::NO_CONTENT,
+                )),
                 Err(error) => Err(action_failure(&device, error)),
             },
         );
 
-    let run = device.and(warp::path("run"))
-    .and(warp::post())
-    .and(warp::body::content_length_limit(1024)) // Should be more than enough
-    .and(warp::body::json::<RunRequest>())
-    .and_then(async move |mut device: Device, request: RunRequest| {
-        let action = Action::Run(TargetId::new(request.target));
-        match device.action(action.clone()).await {
-            Ok(_) => Ok(action_success(&device, action)),
-            Err(error) => Err(action_failure(&device, error))
-        }
-    });
+    let logs = device.and(warp::path("logs")).and(warp::get()).and_then(
+        async move |mut device: Device| match device.tail_logs().await {
+            Ok(entries) => {
+                let events = entries.map(|entry| {
+                    entry.map(|entry| (warp::sse::event(entry.source), warp::sse::data(entry.line)))
+                });
+                Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+            }
+            Err(error) => Err(action_failure(&device, error)),
+        },
+    );
+
+    let group_status = group
+        .clone()
+        .and(warp::path("status"))
+        .and(warp::get())
+        .map(|group: Group| {
+            let response: BTreeMap<String, StatusResponse> = group
+                .devices
+                .iter()
+                .map(|device| (device.id().to_string(), StatusResponse::from(device)))
+                .collect();
+            warp::reply::json(&response)
+        });
+
+    let group_suspend = group
+        .clone()
+        .and(warp::path("suspend"))
+        .and(warp::post())
+        .and(warp::query::<StrategyQuery>())
+        .map(|group: Group, query: StrategyQuery| {
+            group_action(group, Action::Suspend, query.into())
+        });
+
+    let group_shutdown = group
+        .clone()
+        .and(warp::path("shutdown"))
+        .and(warp::post())
+        .and(warp::query::<StrategyQuery>())
+        .map(|group: Group, query: StrategyQuery| {
+            group_action(group, Action::ShutDown, query.into())
+        });
+
+    let group_run = group
+        .and(warp::path("run"))
+        .and(warp::post())
+        .and(warp::query::<StrategyQuery>())
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json::<RunRequest>())
+        .map(|group: Group, query: StrategyQuery, request: RunRequest| {
+            let action = Action::Run(TargetId::new(request.target));
+            group_action(group, action, query.into())
+        });
 
     let api = status
+        .or(targets)
         .or(suspend)
         .or(shutdown)
         .or(reboot)
         .or(run)
+        .or(boot)
+        .or(maintain)
+        .or(maintenance)
+        .or(history)
+        .or(stats)
+        .or(display_on)
+        .or(display_off)
+        .or(hold)
+        .or(release)
+        .or(logs)
+        .or(group_status)
+        .or(group_suspend)
+        .or(group_shutdown)
+        .or(group_run)
+        .or(action_status)
+        .or(cancel_action)
+        .or(cancel)
+        .or(list_schedules)
+        .or(add_schedule)
+        .or(cancel_schedule)
+        .or(reload)
         .recover(move |err| handle_error(logger.clone(), err));
 
     warp::serve(api).run(addr).await
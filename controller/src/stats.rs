@@ -0,0 +1,192 @@
+//! Statistics on how long devices take to boot, shut down, and so on, computed from their history
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::{Event, EventKind};
+use crate::id::TargetId;
+
+/// Number of measurements needed before timeouts are derived from them
+const MIN_SAMPLES: usize = 5;
+
+/// How many times the 95th percentile duration to allow before timing out
+const TIMEOUT_FACTOR: f64 = 2.0;
+
+/// Shortest timeout to derive, so that a few quick boots don't make the next slightly slower one fail
+const MIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A transition whose duration is measured
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// From the first Wake-on-LAN packet until the agent reports the target running
+    Boot,
+    /// From telling the agent to reboot until it reports the target running
+    Reboot,
+    /// From the first Wake-on-LAN packet until a suspended device reports the target running again
+    Resume,
+    /// From telling the agent to shut down until it stops responding
+    Shutdown,
+    /// From telling the agent to suspend until it stops responding
+    Suspend,
+}
+
+/// Summary of the measured durations for one phase and target, in seconds
+#[derive(Debug, Clone, Serialize)]
+pub struct DurationStats {
+    pub samples: usize,
+    pub min: f64,
+    pub median: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+impl DurationStats {
+    /// Summarizes `durations`, which must be sorted and non-empty.
+    fn new(durations: &[f64]) -> DurationStats {
+        // Nearest-rank percentile
+        let percentile = |p: f64| {
+            let rank = (p * durations.len() as f64).ceil() as usize;
+            durations[rank.max(1) - 1]
+        };
+        DurationStats {
+            samples: durations.len(),
+            min: durations[0],
+            median: percentile(0.5),
+            p95: percentile(0.95),
+            max: durations[durations.len() - 1],
+        }
+    }
+}
+
+/// Duration statistics for each phase and target
+pub type Stats = BTreeMap<Phase, BTreeMap<TargetId, DurationStats>>;
+
+/// Computes duration statistics from the measurements in `events`.
+pub fn compute(events: &[Event]) -> Stats {
+    let mut durations: BTreeMap<Phase, BTreeMap<TargetId, Vec<f64>>> = BTreeMap::new();
+    for event in events {
+        if let EventKind::Measured {
+            phase,
+            ref target,
+            seconds,
+        } = event.kind
+        {
+            durations
+                .entry(phase)
+                .or_default()
+                .entry(target.clone())
+                .or_default()
+                .push(seconds);
+        }
+    }
+
+    durations
+        .into_iter()
+        .map(|(phase, targets)| {
+            let targets = targets
+                .into_iter()
+                .map(|(target, mut durations)| {
+                    durations.sort_by(|a, b| a.partial_cmp(b).expect("Durations are never NaN"));
+                    (target, DurationStats::new(&durations))
+                })
+                .collect();
+            (phase, targets)
+        })
+        .collect()
+}
+
+/// Timeout for `phase` of `target` derived from past measurements, if there are enough of them.
+pub fn learned_timeout(stats: &Stats, phase: Phase, target: &TargetId) -> Option<Duration> {
+    let stats = stats.get(&phase)?.get(target)?;
+    if stats.samples < MIN_SAMPLES {
+        return None;
+    }
+    Some(Duration::from_secs_f64(stats.p95 * TIMEOUT_FACTOR).max(MIN_TIMEOUT))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::device::State;
+
+    fn measured(phase: Phase, target: &str, seconds: f64) -> Event {
+        Event {
+            time: Utc::now(),
+            kind: EventKind::Measured {
+                phase,
+                target: TargetId::new(target),
+                seconds,
+            },
+        }
+    }
+
+    fn boots(target: &str, durations: &[f64]) -> Vec<Event> {
+        durations
+            .iter()
+            .map(|seconds| measured(Phase::Boot, target, *seconds))
+            .collect()
+    }
+
+    #[test]
+    fn summarizes_each_phase_and_target() {
+        let mut events = boots("linux", &[40.0, 20.0, 30.0]);
+        events.push(measured(Phase::Shutdown, "linux", 5.0));
+        events.push(measured(Phase::Boot, "windows", 60.0));
+        events.push(Event {
+            time: Utc::now(),
+            kind: EventKind::StateChanged { state: State::Off },
+        });
+
+        let stats = compute(&events);
+        let linux = &stats[&Phase::Boot][&TargetId::new("linux")];
+        assert_eq!(linux.samples, 3);
+        assert_eq!(
+            (linux.min, linux.median, linux.p95, linux.max),
+            (20.0, 30.0, 40.0, 40.0)
+        );
+        assert_eq!(stats[&Phase::Boot][&TargetId::new("windows")].samples, 1);
+        assert_eq!(stats[&Phase::Shutdown][&TargetId::new("linux")].max, 5.0);
+        assert!(!stats.contains_key(&Phase::Reboot));
+    }
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let durations: Vec<f64> = (1..=20).map(f64::from).collect();
+        let stats = DurationStats::new(&durations);
+        assert_eq!((stats.median, stats.p95), (10.0, 19.0));
+
+        let stats = DurationStats::new(&[7.0]);
+        assert_eq!(
+            (stats.min, stats.median, stats.p95, stats.max),
+            (7.0, 7.0, 7.0, 7.0)
+        );
+    }
+
+    #[test]
+    fn learns_timeout_once_there_are_enough_samples() {
+        let linux = TargetId::new("linux");
+        let stats = compute(&boots("linux", &[40.0, 45.0, 50.0, 55.0]));
+        assert_eq!(learned_timeout(&stats, Phase::Boot, &linux), None);
+
+        let stats = compute(&boots("linux", &[40.0, 45.0, 50.0, 55.0, 60.0]));
+        assert_eq!(
+            learned_timeout(&stats, Phase::Boot, &linux),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(learned_timeout(&stats, Phase::Reboot, &linux), None);
+    }
+
+    #[test]
+    fn learned_timeout_has_a_minimum() {
+        let stats = compute(&boots("linux", &[2.0, 3.0, 2.5, 3.5, 4.0]));
+        assert_eq!(
+            learned_timeout(&stats, Phase::Boot, &TargetId::new("linux")),
+            Some(MIN_TIMEOUT)
+        );
+    }
+}
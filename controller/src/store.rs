@@ -0,0 +1,262 @@
+//! Device state and history persisted across controller restarts, as files per device under the state directory
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use slog::{o, warn, Logger};
+use tokio::fs;
+use tokio::sync::Mutex as AsyncMutex;
+
+use crate::device::State;
+use crate::history::{History, Retention};
+use crate::id::{DeviceId, TargetId};
+
+/// Names of controller-wide files in the state directory, without extensions, which device files mustn't clash with
+const RESERVED_NAMES: &[&str] = &["schedules"];
+
+/// What the controller remembers about a device between restarts
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DeviceRecord {
+    /// State last observed by the poller
+    pub state: Option<State>,
+    pub state_changed: Option<DateTime<Utc>>,
+    /// Target the device was last seen running
+    pub last_target: Option<TargetId>,
+    /// Target the device was running when the controller suspended it, if it's still suspended
+    pub suspended: Option<TargetId>,
+    /// Whether the controller turned the device off
+    pub expect_off: bool,
+    pub last_action: Option<String>,
+    pub last_action_started: Option<DateTime<Utc>>,
+    pub last_action_finished: Option<DateTime<Utc>>,
+    /// Why the last action failed, if it did
+    pub last_error: Option<String>,
+}
+
+/// Location of persisted device state. If no state directory is configured, nothing is persisted.
+#[derive(Clone)]
+pub struct StateStore {
+    logger: Logger,
+    directory: Option<PathBuf>,
+    retention: Retention,
+}
+
+impl StateStore {
+    /// Creates a store under `directory`, creating the directory if needed. Device history is kept according to
+    /// `retention`.
+    pub fn new(
+        logger: Logger,
+        directory: Option<PathBuf>,
+        retention: Retention,
+    ) -> Result<StateStore> {
+        if let Some(ref directory) = directory {
+            std::fs::create_dir_all(directory).with_context(|| {
+                format!("Could not create state directory {}", directory.display())
+            })?;
+        }
+        Ok(StateStore {
+            logger,
+            directory,
+            retention,
+        })
+    }
+
+    /// Path of a controller-wide file in the state directory, if there is one.
+    pub fn file(&self, name: &str) -> Option<PathBuf> {
+        self.directory
+            .as_ref()
+            .map(|directory| directory.join(name))
+    }
+
+    /// Opens the event history for `device`.
+    pub fn history(&self, device: &DeviceId) -> History {
+        let logger = self.logger.new(o!("device" => device.clone()));
+        let path = self
+            .directory
+            .as_ref()
+            .map(|directory| directory.join(format!("{}.history.jsonl", file_name(device))));
+        History::open(&logger, path, self.retention)
+    }
+
+    /// Loads the persisted state for `device`. A missing or unreadable state file is treated as an empty record, so
+    /// that a bad file doesn't keep the device from starting.
+    pub fn open(&self, device: &DeviceId) -> DeviceStore {
+        let logger = self.logger.new(o!("device" => device.clone()));
+        let path = self
+            .directory
+            .as_ref()
+            .map(|directory| directory.join(format!("{}.json", file_name(device))));
+
+        // Devices are started synchronously, and this is only a single small file
+        let record = match path.as_ref().map(std::fs::read) {
+            Some(Ok(contents)) => match serde_json::from_slice(&contents) {
+                Ok(record) => record,
+                Err(error) => {
+                    warn!(&logger, "Ignoring invalid state file: {}", error);
+                    DeviceRecord::default()
+                }
+            },
+            Some(Err(error)) if error.kind() != ErrorKind::NotFound => {
+                warn!(&logger, "Could not read state file: {}", error);
+                DeviceRecord::default()
+            }
+            _ => DeviceRecord::default(),
+        };
+
+        DeviceStore {
+            logger,
+            path,
+            record: Arc::new(Mutex::new(record)),
+            write_lock: Arc::new(AsyncMutex::new(())),
+        }
+    }
+}
+
+/// Name to store a device's files under. Characters which aren't safe in file names are percent-encoded, as is a
+/// leading `.` or the first character of a reserved name, so that every device ID gets its own files inside the state
+/// directory. IDs made of letters, digits, `-`, `_`, and `.` are used as they are.
+fn file_name(device: &DeviceId) -> String {
+    let id = device.as_string();
+    let reserved = RESERVED_NAMES.contains(&id.as_str());
+    let mut name = String::with_capacity(id.len());
+    for (index, byte) in id.bytes().enumerate() {
+        let safe = match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => !(reserved && index == 0),
+            b'.' => index > 0,
+            _ => false,
+        };
+        if safe {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    if name.is_empty() {
+        // Can't be produced by any other ID, since a literal `%` is always encoded
+        name.push('%');
+    }
+    name
+}
+
+/// Persisted state for a single device
+#[derive(Clone)]
+pub struct DeviceStore {
+    logger: Logger,
+    path: Option<PathBuf>,
+    record: Arc<Mutex<DeviceRecord>>,
+    /// Held while writing the state file, so that writes don't interleave
+    write_lock: Arc<AsyncMutex<()>>,
+}
+
+impl DeviceStore {
+    /// The current record for the device.
+    pub fn get(&self) -> DeviceRecord {
+        self.record
+            .lock()
+            .expect("Thread panicked with store mutex")
+            .clone()
+    }
+
+    /// Updates the record for the device, writing it to disk in the background if it changed.
+    pub fn update<F: FnOnce(&mut DeviceRecord)>(&self, f: F) {
+        {
+            let mut record = self
+                .record
+                .lock()
+                .expect("Thread panicked with store mutex");
+            let previous = record.clone();
+            f(&mut record);
+            if *record == previous {
+                return;
+            }
+        }
+
+        if self.path.is_some() {
+            let store = self.clone();
+            tokio::spawn(async move {
+                if let Err(error) = store.save().await {
+                    warn!(&store.logger, "Could not save device state: {:#}", error);
+                }
+            });
+        }
+    }
+
+    /// Writes the latest record to the state file. The file is replaced atomically, so it's never left half-written.
+    async fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let _guard = self.write_lock.lock().await;
+        // Serialize while holding the write lock, so the last write always has the latest record
+        let contents = serde_json::to_vec_pretty(&self.get())?;
+        write_atomically(path, contents).await
+    }
+}
+
+/// Replaces the file at `path` with `contents`, by writing them to a temporary file and renaming it over the original,
+/// so that the file is never left half-written.
+pub async fn write_atomically(path: &Path, contents: Vec<u8>) -> Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    fs::write(&temp_path, contents)
+        .await
+        .with_context(|| format!("Could not write {}", temp_path.display()))?;
+    fs::rename(&temp_path, path)
+        .await
+        .with_context(|| format!("Could not replace {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(id: &str) -> String {
+        file_name(&DeviceId::new(id))
+    }
+
+    #[test]
+    fn safe_ids_are_kept() {
+        for id in &["htpc", "my-desktop", "nas_2", "nas.local"] {
+            assert_eq!(name(id), *id);
+        }
+    }
+
+    #[test]
+    fn unsafe_ids_are_encoded() {
+        assert_eq!(name("../escape"), "%2E.%2Fescape");
+        assert_eq!(name(".hidden"), "%2Ehidden");
+        assert_eq!(name("a\\b"), "a%5Cb");
+        assert_eq!(name("two words"), "two%20words");
+        assert_eq!(name("caf\u{e9}"), "caf%C3%A9");
+        assert_eq!(name("100%"), "100%25");
+        assert_eq!(name("schedules"), "%73chedules");
+        assert_eq!(name(""), "%");
+    }
+
+    #[test]
+    fn names_are_distinct() {
+        let ids = [
+            "a b",
+            "a%20b",
+            "a%2520b",
+            "",
+            "%",
+            "schedules",
+            "%73chedules",
+        ];
+        let mut names: Vec<String> = ids.iter().map(|id| name(id)).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), ids.len());
+        assert!(names.iter().all(|name| !name.contains('/')));
+    }
+}
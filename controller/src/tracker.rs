@@ -0,0 +1,131 @@
+//! Progress and results of actions, so that clients can follow up on an action after requesting it
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::id::{ActionId, DeviceId};
+
+/// Number of actions to remember. Once there are more, the oldest are forgotten.
+const MAX_RECORDS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActionStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+/// What has happened to an action so far
+#[derive(Debug, Clone, Serialize)]
+pub struct ActionRecord {
+    pub id: ActionId,
+    pub device: String,
+    pub action: String,
+    pub status: ActionStatus,
+    /// What the device is currently doing for the action, while it's running
+    pub step: Option<String>,
+    /// Number of Wake-on-LAN packets sent to wake the device, if it needed waking
+    pub wake_packets: Option<u32>,
+    pub queued: DateTime<Utc>,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+    /// Why the action failed, if it did
+    pub error: Option<String>,
+}
+
+/// Shared record of recent actions across all devices
+#[derive(Clone, Default)]
+pub struct ActionTracker {
+    inner: Arc<TrackerInner>,
+}
+
+#[derive(Default)]
+struct TrackerInner {
+    next_id: AtomicU64,
+    records: Mutex<BTreeMap<ActionId, ActionRecord>>,
+}
+
+impl ActionTracker {
+    pub fn new() -> ActionTracker {
+        ActionTracker::default()
+    }
+
+    /// Starts tracking a newly-queued action, returning its ID.
+    pub fn queued<A: ToString>(&self, device: &DeviceId, action: &A) -> ActionId {
+        let id = ActionId::new(self.inner.next_id.fetch_add(1, Ordering::Relaxed) + 1);
+        let mut records = self.records();
+        records.insert(
+            id,
+            ActionRecord {
+                id,
+                device: device.to_string(),
+                action: action.to_string(),
+                status: ActionStatus::Queued,
+                step: None,
+                wake_packets: None,
+                queued: Utc::now(),
+                started: None,
+                finished: None,
+                error: None,
+            },
+        );
+        // IDs increase over time, so the first records are the oldest
+        while records.len() > MAX_RECORDS {
+            let oldest = *records.keys().next().expect("Records cannot be empty");
+            records.remove(&oldest);
+        }
+        id
+    }
+
+    pub fn started(&self, id: ActionId) {
+        self.update(id, |record| {
+            record.status = ActionStatus::Running;
+            record.started = Some(Utc::now());
+        });
+    }
+
+    /// Records the step a running action is on.
+    pub fn step<S: Into<String>>(&self, id: ActionId, step: S) {
+        let step = step.into();
+        self.update(id, |record| record.step = Some(step));
+    }
+
+    /// Records how many Wake-on-LAN packets a running action has sent.
+    pub fn wake_packets(&self, id: ActionId, packets: u32) {
+        self.update(id, |record| record.wake_packets = Some(packets));
+    }
+
+    /// Records that an action finished with the given status.
+    pub fn finished(&self, id: ActionId, status: ActionStatus, error: Option<String>) {
+        self.update(id, |record| {
+            record.status = status;
+            record.step = None;
+            record.finished = Some(Utc::now());
+            record.error = error;
+        });
+    }
+
+    pub fn get(&self, id: ActionId) -> Option<ActionRecord> {
+        self.records().get(&id).cloned()
+    }
+
+    fn update<F: FnOnce(&mut ActionRecord)>(&self, id: ActionId, f: F) {
+        if let Some(record) = self.records().get_mut(&id) {
+            f(record);
+        }
+    }
+
+    fn records(&self) -> MutexGuard<'_, BTreeMap<ActionId, ActionRecord>> {
+        self.inner
+            .records
+            .lock()
+            .expect("Thread panicked with action records mutex")
+    }
+}
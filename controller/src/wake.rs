@@ -106,7 +106,7 @@ impl WolSender {
     }
 
     fn send_magic_packet(&mut self, to: MacAddr) -> Result<()> {
-        let source = self.source_address.clone();
+        let source = self.source_address;
         self.datalink_tx
             .build_and_send(1, MAGIC_PACKET_SIZE, &mut |buf| {
                 // Panicking because an error means pnet didn't meet the build_and_send contract